@@ -0,0 +1,81 @@
+//! Wall-clock benchmarks of [`fit`] on the CPU and every accelerator
+//! utf-rnn was built for, for tuning the training loop.
+
+use std::time::{Duration, Instant};
+
+use candle_core::Device;
+
+use crate::error::Result;
+use crate::{cuda_enabled, fit, metal_enabled, Dataset, TrainConfig};
+
+/// Timing of one benchmarked training run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    /// `cpu`, `cuda:0` or `metal:0`.
+    pub device: String,
+    /// Number of epochs that were run.
+    pub epochs: usize,
+    /// Wall time of the whole run, including building the model.
+    pub wall_time: Duration,
+    pub epochs_per_sec: f64,
+}
+
+/// Trains on `dataset` with `config` for exactly `epochs` epochs on every
+/// available device, without early stopping, checkpoints or logging, and
+/// times each run. Runs count whatever accuracy they reach.
+pub fn bench_train(
+    dataset: &Dataset,
+    config: &TrainConfig,
+    epochs: usize,
+) -> Result<Vec<BenchResult>> {
+    let config = TrainConfig {
+        epochs,
+        stop_on_accuracy: false,
+        early_stopping: None,
+        checkpoint_every: None,
+        verbose: false,
+        log_weight_stats: false,
+        ..config.clone()
+    };
+    let mut devices = vec![("cpu".to_string(), Device::Cpu)];
+    if cuda_enabled() {
+        devices.push(("cuda:0".to_string(), Device::new_cuda(0)?));
+    }
+    if metal_enabled() {
+        devices.push(("metal:0".to_string(), Device::new_metal(0)?));
+    }
+
+    let mut results = Vec::with_capacity(devices.len());
+    for (name, dev) in devices {
+        let start = Instant::now();
+        // Every epoch reads its losses back to the host, so the device is
+        // done once `fit` returns.
+        let trained = fit(dataset.clone(), &config, &dev, None)?.trained;
+        let wall_time = start.elapsed();
+        results.push(BenchResult {
+            device: name,
+            epochs: trained.metrics.len(),
+            wall_time,
+            epochs_per_sec: trained.metrics.len() as f64 / wall_time.as_secs_f64(),
+        });
+    }
+    Ok(results)
+}
+
+/// Formats `results` as a table with one row per device.
+pub fn comparison_table(results: &[BenchResult]) -> String {
+    let mut table = format!(
+        "{:<8} {:>8} {:>12} {:>12}\n",
+        "device", "epochs", "wall time", "epochs/s"
+    );
+    for result in results {
+        table.push_str(&format!(
+            "{:<8} {:>8} {:>11.3}s {:>12.1}\n",
+            result.device,
+            result.epochs,
+            result.wall_time.as_secs_f64(),
+            result.epochs_per_sec
+        ));
+    }
+    table
+}
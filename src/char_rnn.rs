@@ -0,0 +1,274 @@
+use candle_core::{Device, Tensor, D};
+use candle_nn::rnn::LSTMState;
+use candle_nn::{
+    loss, ops, Embedding, LSTMConfig, Linear, Module, Optimizer, ParamsAdamW, VarBuilder, VarMap,
+    LSTM, RNN,
+};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::Rng;
+use tracing::{info, info_span};
+
+use crate::error::{Result, UtfRnnError};
+use crate::tokenizer::CharVocab;
+use crate::Dataset;
+
+/// Temperatures at or below this pick the most likely character instead of
+/// sampling.
+const GREEDY_TEMPERATURE: f32 = 1e-3;
+
+/// A character-level language model over the ids of a [`CharVocab`], which
+/// [`train`], [`train_stream`] and [`perplexity`] work with.
+pub trait CharModel {
+    /// Takes a `(batch, seq_len)` tensor of `u32` codepoint indices and
+    /// returns `(batch, seq_len, vocab_size)` logits, where position `t`
+    /// scores the character following `seq[.., t]`.
+    fn forward(&self, seq: &Tensor) -> Result<Tensor>;
+}
+
+/// Character-level language model: embeds a sequence of codepoint indices,
+/// runs it through an LSTM and projects every hidden state onto the
+/// vocabulary to score the next character.
+pub struct CharRnn {
+    embedding: Embedding,
+    lstm: LSTM,
+    head: Linear,
+}
+
+impl CharRnn {
+    pub fn new(
+        vocab_size: usize,
+        embed_dim: usize,
+        hidden_dim: usize,
+        vs: VarBuilder,
+    ) -> Result<Self> {
+        let embedding = candle_nn::embedding(vocab_size, embed_dim, vs.pp("embedding"))?;
+        let lstm = candle_nn::lstm(embed_dim, hidden_dim, LSTMConfig::default(), vs.pp("lstm"))?;
+        let head = candle_nn::linear(hidden_dim, vocab_size, vs.pp("head"))?;
+        Ok(Self {
+            embedding,
+            lstm,
+            head,
+        })
+    }
+
+    /// Takes a `(batch, seq_len)` tensor of `u32` codepoint indices and
+    /// returns `(batch, seq_len, vocab_size)` logits, where position `t`
+    /// scores the character following `seq[.., t]`.
+    pub fn forward(&self, seq: &Tensor) -> Result<Tensor> {
+        // Look up an embedding for every index: (batch, seq_len, embed_dim)
+        let xs = self.embedding.forward(seq)?;
+        // Unroll the LSTM over the sequence: (batch, seq_len, hidden_dim)
+        let states = self.lstm.seq(&xs)?;
+        let hidden = self.lstm.states_to_tensor(&states)?;
+        // Score the next character at every position
+        self.head.forward(&hidden).map_err(Into::into)
+    }
+
+    /// Continues `prompt` with up to `max_len` characters sampled one at a
+    /// time from the temperature-scaled softmax of the next-character
+    /// logits, and returns the prompt followed by them. A `temperature`
+    /// close to `0` always picks the most likely character. With `top_p`,
+    /// only the most likely characters whose cumulative probability reaches
+    /// `top_p` are candidates (nucleus sampling). Characters of the prompt
+    /// missing from `vocab` are skipped, but at least one must remain.
+    pub fn generate(
+        &self,
+        vocab: &CharVocab,
+        prompt: &str,
+        max_len: usize,
+        temperature: f32,
+        top_p: Option<f32>,
+    ) -> Result<String> {
+        let prompt_ids = vocab.encode(prompt);
+        if prompt_ids.is_empty() {
+            return Err(UtfRnnError::InvalidInput(
+                "the prompt contains no character of the vocabulary".to_string(),
+            ));
+        }
+        let device = self.head.weight().device();
+        let mut rng = rand::thread_rng();
+
+        // Run the prompt through the LSTM one character at a time.
+        let mut state = self.lstm.zero_state(1)?;
+        for &id in &prompt_ids {
+            state = self.step(id, &state, device)?;
+        }
+
+        let mut text = vocab.decode(&prompt_ids);
+        for _ in 0..max_len {
+            // Score the next character from the latest hidden state: (vocab_size,)
+            let logits = self.head.forward(state.h())?.squeeze(0)?;
+            let id = sample_next(&logits, temperature, top_p, &mut rng)?;
+            match vocab.char(id) {
+                Some(c) => text.push(c),
+                // The head scores more ids than the vocabulary holds.
+                None => break,
+            }
+            state = self.step(id, &state, device)?;
+        }
+        Ok(text)
+    }
+
+    /// Advances the LSTM `state` by the single character `id`.
+    fn step(&self, id: u32, state: &LSTMState, device: &Device) -> Result<LSTMState> {
+        // (1,) id -> (1, embed_dim) embedding
+        let xs = self.embedding.forward(&Tensor::new(&[id], device)?)?;
+        Ok(self.lstm.step(&xs, state)?)
+    }
+}
+
+impl CharModel for CharRnn {
+    fn forward(&self, seq: &Tensor) -> Result<Tensor> {
+        CharRnn::forward(self, seq)
+    }
+}
+
+/// Picks the next character id from `logits`, greedily at a temperature close
+/// to `0` and by sampling the temperature-scaled softmax otherwise, restricted
+/// to the top-`top_p` nucleus if given.
+pub(crate) fn sample_next<R: Rng>(
+    logits: &Tensor,
+    temperature: f32,
+    top_p: Option<f32>,
+    rng: &mut R,
+) -> Result<u32> {
+    if temperature <= GREEDY_TEMPERATURE {
+        return Ok(logits.argmax(D::Minus1)?.to_scalar::<u32>()?);
+    }
+    let mut probabilities =
+        ops::softmax(&(logits / temperature as f64)?, D::Minus1)?.to_vec1::<f32>()?;
+    if let Some(top_p) = top_p {
+        keep_nucleus(&mut probabilities, top_p);
+    }
+    let distribution = WeightedIndex::new(&probabilities)
+        .map_err(|e| UtfRnnError::InvalidInput(format!("cannot sample the next character: {e}")))?;
+    Ok(distribution.sample(rng) as u32)
+}
+
+/// Zeroes every probability outside the smallest set of most likely entries
+/// whose sum reaches `top_p`. The most likely entry is always kept; sampling
+/// from the remaining weights renormalizes them.
+fn keep_nucleus(probabilities: &mut [f32], top_p: f32) {
+    let mut order: Vec<usize> = (0..probabilities.len()).collect();
+    order.sort_unstable_by(|&a, &b| probabilities[b].total_cmp(&probabilities[a]));
+    let mut cumulative = 0.0;
+    let mut kept = 0;
+    for &i in &order {
+        if kept > 0 && cumulative >= top_p {
+            break;
+        }
+        cumulative += probabilities[i];
+        kept += 1;
+    }
+    for &i in &order[kept..] {
+        probabilities[i] = 0.0;
+    }
+}
+
+/// Mean cross-entropy of `model` predicting every next character of the
+/// `(batch, seq_len)` `targets` from the `inputs` of the same shape.
+fn next_char_loss(model: &impl CharModel, inputs: &Tensor, targets: &Tensor) -> Result<Tensor> {
+    let logits = model.forward(inputs)?;
+    let (batch, seq_len, vocab_size) = logits.dims3()?;
+    Ok(loss::cross_entropy(
+        &logits.reshape((batch * seq_len, vocab_size))?,
+        &targets.flatten_all()?,
+    )?)
+}
+
+/// Perplexity of `model` on the `(batch, len)` sequences of character ids in
+/// `data`: `exp` of the mean cross-entropy of predicting every character from
+/// the ones before it. A model guessing uniformly scores the vocabulary size.
+pub fn perplexity(model: &impl CharModel, data: &Tensor, dev: &Device) -> Result<f32> {
+    let data = data.to_device(dev)?;
+    let len = data.dim(1)?;
+    if len < 2 {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "perplexity needs sequences of at least 2 characters, got {len}"
+        )));
+    }
+    let inputs = data.narrow(1, 0, len - 1)?;
+    let targets = data.narrow(1, 1, len - 1)?;
+    let loss = next_char_loss(model, &inputs, &targets)?.to_scalar::<f32>()?;
+    Ok(loss.exp())
+}
+
+/// Joins the input windows of [`Dataset::from_text_file`] with the last
+/// character of their targets, giving the full `(batch, seq_len + 1)`
+/// sequences.
+fn full_sequences(inputs: &Tensor, targets: &Tensor) -> Result<Tensor> {
+    let seq_len = targets.dim(1)?;
+    Ok(Tensor::cat(
+        &[inputs, &targets.narrow(1, seq_len - 1, 1)?],
+        1,
+    )?)
+}
+
+/// AdamW over every variable in `varmap`.
+fn adamw(varmap: &VarMap, learning_rate: f64) -> Result<candle_nn::AdamW> {
+    let params = ParamsAdamW {
+        lr: learning_rate,
+        ..Default::default()
+    };
+    Ok(candle_nn::AdamW::new(varmap.all_vars(), params)?)
+}
+
+/// Trains `model`, whose variables live in `varmap`, on the windows of a
+/// [`Dataset::from_text_file`] dataset with AdamW, and returns the test
+/// perplexity after every epoch. See [`train_stream`] for corpora that don't
+/// fit in memory.
+pub fn train(
+    model: &impl CharModel,
+    varmap: &VarMap,
+    m: &Dataset,
+    epochs: usize,
+    learning_rate: f64,
+    dev: &Device,
+) -> Result<Vec<f32>> {
+    let _span = info_span!("train_char_rnn", epochs).entered();
+    let train_inputs = m.train_votes.to_device(dev)?;
+    let train_targets = m.train_results.to_device(dev)?;
+    let test_sequences = full_sequences(&m.test_votes, &m.test_results)?;
+
+    let mut optimizer = adamw(varmap, learning_rate)?;
+    let mut perplexities = Vec::with_capacity(epochs);
+    for epoch in 1..epochs + 1 {
+        let loss = next_char_loss(model, &train_inputs, &train_targets)?;
+        optimizer.backward_step(&loss)?;
+
+        let test_perplexity = perplexity(model, &test_sequences, dev)?;
+        info!(
+            epoch,
+            train_loss = loss.to_scalar::<f32>()?,
+            perplexity = test_perplexity,
+            "epoch finished"
+        );
+        perplexities.push(test_perplexity);
+    }
+    Ok(perplexities)
+}
+
+/// Makes one AdamW pass over the `(inputs, targets)` batches of e.g. a
+/// [`SequenceStream`](crate::stream::SequenceStream), taking a step per batch, and returns the training loss
+/// of every batch. Open a new stream for every further epoch.
+pub fn train_stream(
+    model: &impl CharModel,
+    varmap: &VarMap,
+    batches: impl IntoIterator<Item = Result<(Tensor, Tensor)>>,
+    learning_rate: f64,
+    dev: &Device,
+) -> Result<Vec<f32>> {
+    let _span = info_span!("train_char_rnn_stream").entered();
+    let mut optimizer = adamw(varmap, learning_rate)?;
+    let mut losses = Vec::new();
+    for (batch, item) in batches.into_iter().enumerate() {
+        let (inputs, targets) = item?;
+        let loss = next_char_loss(model, &inputs.to_device(dev)?, &targets.to_device(dev)?)?;
+        optimizer.backward_step(&loss)?;
+
+        let loss = loss.to_scalar::<f32>()?;
+        info!(batch, train_loss = loss, "batch finished");
+        losses.push(loss);
+    }
+    Ok(losses)
+}
@@ -0,0 +1,183 @@
+use candle_core::{Device, Tensor, D};
+use candle_nn::{ops, Embedding, Linear, Module, VarBuilder};
+
+use crate::char_rnn::{sample_next, CharModel};
+use crate::error::{Result, UtfRnnError};
+use crate::tokenizer::CharVocab;
+
+/// Longest sequence the positional embeddings cover; generation only looks
+/// at this many most recent characters.
+pub const MAX_SEQ_LEN: usize = 256;
+
+/// Layer normalization over the last dimension, built from differentiable
+/// tensor ops so it can be trained.
+struct LayerNorm {
+    weight: Tensor,
+    bias: Tensor,
+}
+
+impl LayerNorm {
+    const EPS: f64 = 1e-5;
+
+    fn new(size: usize, vs: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            weight: vs.get_with_hints(size, "weight", candle_nn::Init::Const(1.))?,
+            bias: vs.get_with_hints(size, "bias", candle_nn::Init::Const(0.))?,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let centered = xs.broadcast_sub(&xs.mean_keepdim(D::Minus1)?)?;
+        let std = (centered.sqr()?.mean_keepdim(D::Minus1)? + Self::EPS)?.sqrt()?;
+        Ok(centered
+            .broadcast_div(&std)?
+            .broadcast_mul(&self.weight)?
+            .broadcast_add(&self.bias)?)
+    }
+}
+
+/// Character-level language model with a single pre-norm transformer block:
+/// learned token and position embeddings, causal multi-head self-attention
+/// and a feed-forward layer, each added back onto its input, followed by a
+/// projection onto the vocabulary.
+pub struct CharTransformer {
+    embedding: Embedding,
+    positions: Embedding,
+    attention_norm: LayerNorm,
+    /// Projects every position onto its concatenated queries, keys and values.
+    qkv: Linear,
+    attention_out: Linear,
+    feed_forward_norm: LayerNorm,
+    feed_forward_in: Linear,
+    feed_forward_out: Linear,
+    head: Linear,
+    n_heads: usize,
+}
+
+impl CharTransformer {
+    pub fn new(vocab_size: usize, d_model: usize, n_heads: usize, vs: VarBuilder) -> Result<Self> {
+        if n_heads == 0 || d_model % n_heads != 0 {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "d_model {d_model} must be a positive multiple of n_heads {n_heads}"
+            )));
+        }
+        Ok(Self {
+            embedding: candle_nn::embedding(vocab_size, d_model, vs.pp("embedding"))?,
+            positions: candle_nn::embedding(MAX_SEQ_LEN, d_model, vs.pp("positions"))?,
+            attention_norm: LayerNorm::new(d_model, vs.pp("attention_norm"))?,
+            qkv: candle_nn::linear(d_model, 3 * d_model, vs.pp("qkv"))?,
+            attention_out: candle_nn::linear(d_model, d_model, vs.pp("attention_out"))?,
+            feed_forward_norm: LayerNorm::new(d_model, vs.pp("feed_forward_norm"))?,
+            feed_forward_in: candle_nn::linear(d_model, 4 * d_model, vs.pp("feed_forward_in"))?,
+            feed_forward_out: candle_nn::linear(4 * d_model, d_model, vs.pp("feed_forward_out"))?,
+            head: candle_nn::linear(d_model, vocab_size, vs.pp("head"))?,
+            n_heads,
+        })
+    }
+
+    /// Takes a `(batch, seq_len)` tensor of `u32` codepoint indices, with
+    /// `seq_len` at most [`MAX_SEQ_LEN`], and returns `(batch, seq_len,
+    /// vocab_size)` logits, where position `t` scores the character following
+    /// `seq[.., t]` from the characters up to it.
+    pub fn forward(&self, seq: &Tensor) -> Result<Tensor> {
+        let (_, seq_len) = seq.dims2()?;
+        if seq_len > MAX_SEQ_LEN {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "sequences can be at most {MAX_SEQ_LEN} characters long, got {seq_len}"
+            )));
+        }
+        let device = seq.device();
+        // (batch, seq_len, d_model) token plus (seq_len, d_model) position embeddings
+        let positions = Tensor::arange(0u32, seq_len as u32, device)?;
+        let xs = self
+            .embedding
+            .forward(seq)?
+            .broadcast_add(&self.positions.forward(&positions)?)?;
+        let xs = (&xs + self.attention(&self.attention_norm.forward(&xs)?)?)?;
+        let hidden = self
+            .feed_forward_in
+            .forward(&self.feed_forward_norm.forward(&xs)?)?
+            .gelu()?;
+        let xs = (&xs + self.feed_forward_out.forward(&hidden)?)?;
+        // Score the next character at every position
+        Ok(self.head.forward(&xs)?)
+    }
+
+    /// Causal multi-head self-attention over the `(batch, seq_len, d_model)`
+    /// tensor `xs`.
+    fn attention(&self, xs: &Tensor) -> Result<Tensor> {
+        let (batch, seq_len, d_model) = xs.dims3()?;
+        let head_dim = d_model / self.n_heads;
+        let qkv = self.qkv.forward(xs)?;
+        // (batch, seq_len, d_model) -> (batch, n_heads, seq_len, head_dim)
+        let split = |i: usize| -> Result<Tensor> {
+            Ok(qkv
+                .narrow(2, i * d_model, d_model)?
+                .reshape((batch, seq_len, self.n_heads, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?)
+        };
+        let (q, k, v) = (split(0)?, split(1)?, split(2)?);
+
+        // (batch, n_heads, seq_len, seq_len) scores, hiding the later positions
+        let scores = (q.matmul(&k.t()?.contiguous()?)? / (head_dim as f64).sqrt())?;
+        let mask = causal_mask(seq_len, xs.device())?.to_dtype(scores.dtype())?;
+        let scores = scores.broadcast_add(&mask)?;
+        let weights = ops::softmax(&scores, D::Minus1)?;
+        let ys = weights
+            .matmul(&v)?
+            .transpose(1, 2)?
+            .reshape((batch, seq_len, d_model))?;
+        Ok(self.attention_out.forward(&ys)?)
+    }
+
+    /// Continues `prompt` with up to `max_len` characters sampled one at a
+    /// time, like [`CharRnn::generate`](crate::char_rnn::CharRnn::generate).
+    /// Every step reruns the model on the last [`MAX_SEQ_LEN`] characters.
+    pub fn generate(
+        &self,
+        vocab: &CharVocab,
+        prompt: &str,
+        max_len: usize,
+        temperature: f32,
+        top_p: Option<f32>,
+    ) -> Result<String> {
+        let mut ids = vocab.encode(prompt);
+        if ids.is_empty() {
+            return Err(UtfRnnError::InvalidInput(
+                "the prompt contains no character of the vocabulary".to_string(),
+            ));
+        }
+        let device = self.head.weight().device();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..max_len {
+            let context = &ids[ids.len().saturating_sub(MAX_SEQ_LEN)..];
+            let seq = Tensor::new(context, device)?.unsqueeze(0)?;
+            // Score the character after the last one: (vocab_size,)
+            let logits = self.forward(&seq)?.squeeze(0)?.get(context.len() - 1)?;
+            let id = sample_next(&logits, temperature, top_p, &mut rng)?;
+            if vocab.char(id).is_none() {
+                // The head scores more ids than the vocabulary holds.
+                break;
+            }
+            ids.push(id);
+        }
+        Ok(vocab.decode(&ids))
+    }
+}
+
+impl CharModel for CharTransformer {
+    fn forward(&self, seq: &Tensor) -> Result<Tensor> {
+        CharTransformer::forward(self, seq)
+    }
+}
+
+/// `(seq_len, seq_len)` mask adding `-inf` to the scores of every position
+/// after the querying one.
+fn causal_mask(seq_len: usize, device: &Device) -> Result<Tensor> {
+    let mask: Vec<f32> = (0..seq_len)
+        .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0.0 }))
+        .collect();
+    Ok(Tensor::from_vec(mask, (seq_len, seq_len), device)?)
+}
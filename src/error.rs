@@ -0,0 +1,77 @@
+/// Error returned when training cannot continue.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum TrainError {
+    /// The training or test loss became NaN or infinite, e.g. because the
+    /// learning rate is too high.
+    #[error("The loss is not finite at epoch {epoch}; try a lower learning rate.")]
+    NonFiniteLoss { epoch: usize },
+}
+
+/// Every failure the crate can report.
+#[derive(Debug, thiserror::Error)]
+pub enum UtfRnnError {
+    /// A single training run finished below the target test accuracy.
+    #[error(
+        "The model is not trained well enough after {epochs} epochs (accuracy: {accuracy:5.2}%, {:.2} points short of {target:.2}%).",
+        .target - .accuracy
+    )]
+    NotTrained {
+        epochs: usize,
+        accuracy: f32,
+        target: f32,
+    },
+    /// Every attempt of `train_until_converged` finished below the target
+    /// test accuracy.
+    #[error(
+        "The model did not converge after {attempts} attempts (best accuracy: {best_accuracy:5.2}%, {:.2} points short of {target:.2}%).",
+        .target - .best_accuracy
+    )]
+    NotConverged {
+        attempts: usize,
+        best_accuracy: f32,
+        target: f32,
+    },
+    #[error(transparent)]
+    Train(#[from] TrainError),
+    /// A tensor does not have the shape the model or config expects.
+    #[error("{name} has shape {got:?} but {expected:?} was expected")]
+    ShapeMismatch {
+        name: String,
+        expected: Vec<usize>,
+        got: Vec<usize>,
+    },
+    /// A tensor has a different dtype than the one it is used as.
+    #[error("{name} has dtype {got:?} but {expected:?} was expected")]
+    DTypeMismatch {
+        name: String,
+        expected: candle_core::DType,
+        got: candle_core::DType,
+    },
+    /// A class label is not below the number of classes.
+    #[error("{name} contain the label {label}, but there are only {num_classes} classes")]
+    LabelOutOfRange {
+        name: String,
+        label: usize,
+        num_classes: usize,
+    },
+    /// A weights file lacks a tensor the model needs.
+    #[error("{path} does not contain the tensor {name}")]
+    MissingTensor { path: String, name: String },
+    /// The requested device is unknown, its backend was not compiled in, or
+    /// this machine has no such device.
+    #[error("{0}")]
+    Device(String),
+    /// A dataset, config value or argument is malformed.
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error(transparent)]
+    Candle(#[from] candle_core::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+}
+
+pub type Result<T, E = UtfRnnError> = std::result::Result<T, E>;
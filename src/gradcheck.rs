@@ -0,0 +1,95 @@
+//! Finite-difference checks of the gradients candle's autograd computes for
+//! [`MultiLevelPerceptron`], for verifying new layers and activations.
+
+use candle_core::{DType, Tensor, Var};
+
+use crate::error::{Result, UtfRnnError};
+use crate::{Loss, MultiLevelPerceptron};
+
+/// Step of the central differences. Small enough to rarely cross a ReLU kink.
+const EPSILON: f32 = 1e-3;
+/// Gradients below this magnitude are compared absolutely, as the `F32`
+/// rounding of the loss swamps their relative error.
+const MIN_SCALE: f32 = 1e-2;
+
+/// Compares the autograd gradient of the cross-entropy of `model` on the
+/// `(n, vote_dim)` `input` and its `n` class `label`s with central finite
+/// differences, for every weight, bias and embedding value, and returns the
+/// largest relative error `|analytic - numerical| / max(|analytic|,
+/// |numerical|)`.
+///
+/// Every value is perturbed in place and restored afterwards, so this runs
+/// `2 * num_parameters` forward passes.
+pub fn gradcheck(model: &MultiLevelPerceptron, input: &Tensor, label: &Tensor) -> Result<f32> {
+    let mut params: Vec<&Tensor> = model
+        .embedding
+        .iter()
+        .map(|embedding| embedding.embeddings())
+        .collect();
+    for layer in &model.layers {
+        params.push(layer.weight());
+        params.extend(layer.bias());
+    }
+    let vars = params
+        .into_iter()
+        .map(|param| {
+            if !param.is_variable() {
+                return Err(UtfRnnError::InvalidInput(
+                    "gradcheck needs a model whose weights are variables".to_string(),
+                ));
+            }
+            Ok(Var::from_tensor(param)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let loss = || -> Result<Tensor> { Loss::CrossEntropy.compute(&model.forward(input)?, label) };
+    let grads = loss()?.backward()?;
+    let mut max_error = 0f32;
+    for var in &vars {
+        let analytic = match grads.get(var) {
+            Some(grad) => grad.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?,
+            None => vec![0.0; var.elem_count()],
+        };
+        let original = var.as_tensor().copy()?;
+        let error = max_error_of(var, &original, &analytic, &loss);
+        // Put the weights back even when a forward pass failed.
+        var.set(&original)?;
+        max_error = max_error.max(error?);
+    }
+    Ok(max_error)
+}
+
+/// Largest relative error between `analytic` and the central differences of
+/// `loss` in every value of `var`, which starts out equal to `original`.
+fn max_error_of(
+    var: &Var,
+    original: &Tensor,
+    analytic: &[f32],
+    loss: &dyn Fn() -> Result<Tensor>,
+) -> Result<f32> {
+    let values = original
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let loss_with = |values: &[f32]| -> Result<f32> {
+        let shifted = Tensor::from_slice(values, original.shape(), original.device())?
+            .to_dtype(original.dtype())?;
+        var.set(&shifted)?;
+        Ok(loss()?.to_scalar::<f32>()?)
+    };
+
+    let mut max_error = 0f32;
+    let mut shifted = values.clone();
+    for (i, &analytic) in analytic.iter().enumerate() {
+        shifted[i] = values[i] + EPSILON;
+        let plus = loss_with(&shifted)?;
+        shifted[i] = values[i] - EPSILON;
+        let minus = loss_with(&shifted)?;
+        shifted[i] = values[i];
+
+        let numerical = (plus - minus) / (2.0 * EPSILON);
+        let scale = analytic.abs().max(numerical.abs()).max(MIN_SCALE);
+        max_error = max_error.max((analytic - numerical).abs() / scale);
+    }
+    Ok(max_error)
+}
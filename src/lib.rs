@@ -0,0 +1,3534 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use candle_core::{backprop::GradStore, Var};
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::{
+    loss, ops, Dropout, Embedding, Linear, Module, Optimizer as _, ParamsAdamW, VarBuilder, VarMap,
+};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tracing::{info, info_span, warn};
+
+use crate::error::{Result, TrainError, UtfRnnError};
+use crate::tokenizer::CharVocab;
+
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod char_rnn;
+pub mod char_transformer;
+pub mod error;
+#[cfg(feature = "debug-tools")]
+pub mod gradcheck;
+pub mod onnx;
+pub mod predictor;
+pub mod stream;
+#[cfg(test)]
+mod test;
+pub mod tokenizer;
+
+pub const VOTE_DIM: usize = 2;
+const RESULTS: usize = 1;
+pub const EPOCHS: usize = 10;
+const LAYER1_OUT_SIZE: usize = 4;
+const LAYER2_OUT_SIZE: usize = 2;
+pub const LEARNING_RATE: f64 = 0.05;
+pub const MAX_ATTEMPTS: usize = 100;
+/// Fraction of the samples the loaders put into the training set.
+const DEFAULT_TRAIN_RATIO: f32 = 0.8;
+/// Names of the standardizer statistics saved next to the weights.
+const STANDARDIZER_MEAN: &str = "standardizer.mean";
+const STANDARDIZER_STD: &str = "standardizer.std";
+
+/// Optimization algorithm used to update the perceptron weights.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Optimizer {
+    Sgd,
+    AdamW {
+        beta1: f64,
+        beta2: f64,
+        weight_decay: f64,
+    },
+}
+
+/// Non-linearity applied between the hidden layers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Activation {
+    #[default]
+    Relu,
+    Gelu,
+    Tanh,
+    Sigmoid,
+}
+
+/// How the layer weights are initialized. The biases always start uniform in
+/// `±1/sqrt(fan_in)`.
+///
+/// Kaiming (He) initialization keeps the activation variance steady through
+/// ReLU and GELU layers, which zero about half of their inputs. Xavier
+/// (Glorot) initialization suits the symmetric Tanh and Sigmoid, whose
+/// variance is preserved in both the forward and backward pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Init {
+    /// Candle's default, a Kaiming normal draw with standard deviation
+    /// `sqrt(2 / fan_in)`.
+    #[default]
+    Default,
+    /// Uniform in `±sqrt(6 / fan_in)`, giving a variance of `2 / fan_in`.
+    KaimingUniform,
+    /// Normal with standard deviation `sqrt(2 / (fan_in + fan_out))`.
+    XavierNormal,
+}
+
+impl Init {
+    /// The candle initializer of a `(fan_out, fan_in)` weight.
+    fn weight_init(self, fan_in: usize, fan_out: usize) -> candle_nn::Init {
+        match self {
+            Self::Default => candle_nn::init::DEFAULT_KAIMING_NORMAL,
+            Self::KaimingUniform => candle_nn::init::DEFAULT_KAIMING_UNIFORM,
+            Self::XavierNormal => candle_nn::Init::Randn {
+                mean: 0.,
+                stdev: (2. / (fan_in + fan_out) as f64).sqrt(),
+            },
+        }
+    }
+
+    /// Draws a `(fan_out, fan_in)` weight from this distribution with `rng`.
+    fn sample(self, fan_in: usize, fan_out: usize, rng: &mut StdRng) -> Vec<f32> {
+        let count = fan_in * fan_out;
+        match self {
+            Self::Default => {
+                let std = (2. / fan_in as f32).sqrt();
+                (0..count).map(|_| std * standard_normal(rng)).collect()
+            }
+            Self::KaimingUniform => {
+                let bound = (6. / fan_in as f32).sqrt();
+                (0..count).map(|_| rng.gen_range(-bound..bound)).collect()
+            }
+            Self::XavierNormal => {
+                let std = (2. / (fan_in + fan_out) as f32).sqrt();
+                (0..count).map(|_| std * standard_normal(rng)).collect()
+            }
+        }
+    }
+}
+
+/// What the perceptron is trained to predict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Task {
+    /// Score `num_classes` classes, trained with the configured [`Loss`].
+    #[default]
+    Classification,
+    /// Output a single continuous value per row, trained with the mean
+    /// squared error against `f32` results.
+    Regression,
+    /// Decide `num_labels` independent yes/no labels per row, trained with
+    /// the binary cross-entropy of the sigmoid outputs against `(n,
+    /// num_labels)` results of `0.0`/`1.0`. A label is predicted when its
+    /// probability exceeds `0.5`.
+    MultiLabel { num_labels: usize },
+}
+
+/// Format of the per-epoch metrics reported by `verbose` training.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MetricsFormat {
+    /// An `info` event with the main fields of [`EpochMetrics`].
+    #[default]
+    Human,
+    /// One JSON object with every field of [`EpochMetrics`] per line on
+    /// stdout, e.g. for piping into monitoring tools.
+    JsonLines,
+}
+
+/// How the vote columns are fed into the perceptron.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum InputMode {
+    /// Every column is a number passed straight to the first layer.
+    #[default]
+    Float,
+    /// Every column is a category id below `vocab_size`, looked up in a
+    /// learned `embed_dim`-wide embedding before the first layer.
+    Categorical { vocab_size: usize, embed_dim: usize },
+}
+
+/// Applies `activation` element-wise to `xs`.
+fn apply_activation(xs: &Tensor, activation: Activation) -> Result<Tensor> {
+    let xs = match activation {
+        Activation::Relu => xs.relu()?,
+        Activation::Gelu => xs.gelu()?,
+        Activation::Tanh => xs.tanh()?,
+        Activation::Sigmoid => ops::sigmoid(xs)?,
+    };
+    Ok(xs)
+}
+
+/// Classification loss minimized during training.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Loss {
+    /// `loss::cross_entropy` on the raw logits.
+    #[default]
+    CrossEntropy,
+    /// An explicit `log_softmax` followed by `loss::nll`.
+    LogSoftmaxNll,
+}
+
+impl Loss {
+    /// Mean loss of the `(n, num_classes)` `logits` against the `n` class
+    /// `labels`.
+    pub fn compute(&self, logits: &Tensor, labels: &Tensor) -> Result<Tensor> {
+        let loss = match self {
+            Self::CrossEntropy => loss::cross_entropy(logits, labels)?,
+            Self::LogSoftmaxNll => loss::nll(&ops::log_softmax(logits, D::Minus1)?, labels)?,
+        };
+        Ok(loss)
+    }
+
+    /// Like [`Loss::compute`], but with `class_weights` every sample's loss is
+    /// scaled by the weight of its label and the sum is divided by the total
+    /// weight of the batch.
+    pub fn compute_weighted(
+        &self,
+        logits: &Tensor,
+        labels: &Tensor,
+        class_weights: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let Some(class_weights) = class_weights else {
+            return self.compute(logits, labels);
+        };
+        // Both variants reduce to the same per-sample negative log-likelihood.
+        let per_sample = ops::log_softmax(logits, D::Minus1)?
+            .gather(&labels.unsqueeze(1)?, 1)?
+            .squeeze(1)?
+            .neg()?;
+        let weights = class_weights.index_select(labels, 0)?;
+        Ok(((per_sample * &weights)?.sum_all()? / weights.sum_all()?)?)
+    }
+
+    /// Like [`Loss::compute_weighted`], against the one-hot targets mixed
+    /// with the uniform distribution: `1 - label_smoothing` of the weight
+    /// stays on the label and `label_smoothing` is spread over all classes.
+    /// `0.0` gives exactly the unsmoothed loss.
+    pub fn compute_smoothed(
+        &self,
+        logits: &Tensor,
+        labels: &Tensor,
+        class_weights: Option<&Tensor>,
+        label_smoothing: f32,
+    ) -> Result<Tensor> {
+        if label_smoothing == 0.0 {
+            return self.compute_weighted(logits, labels, class_weights);
+        }
+        let log_probs = ops::log_softmax(logits, D::Minus1)?;
+        let nll = log_probs
+            .gather(&labels.unsqueeze(1)?, 1)?
+            .squeeze(1)?
+            .neg()?;
+        // Cross-entropy against the uniform distribution.
+        let uniform = log_probs.mean(D::Minus1)?.neg()?;
+        let smoothing = label_smoothing as f64;
+        let per_sample = ((nll * (1. - smoothing))? + (uniform * smoothing)?)?;
+        match class_weights {
+            Some(class_weights) => {
+                let weights = class_weights.index_select(labels, 0)?;
+                Ok(((per_sample * &weights)?.sum_all()? / weights.sum_all()?)?)
+            }
+            None => Ok(per_sample.mean_all()?),
+        }
+    }
+}
+
+/// Whether a CSV cell holds no value: empty, `NA` or `NaN`.
+fn is_missing(value: &str) -> bool {
+    value.is_empty() || value.eq_ignore_ascii_case("na") || value.eq_ignore_ascii_case("nan")
+}
+
+/// Inverse-frequency class weights for `results`: class `c` gets
+/// `n / (num_classes * count_c)`, so every class contributes equally to a
+/// weighted loss. Classes that never occur get weight `0`.
+pub fn inverse_frequency_weights(results: &Tensor, num_classes: usize) -> Result<Vec<f32>> {
+    let labels = results.to_vec1::<u32>()?;
+    let mut counts = vec![0usize; num_classes];
+    for &label in &labels {
+        match counts.get_mut(label as usize) {
+            Some(count) => *count += 1,
+            None => {
+                return Err(UtfRnnError::InvalidInput(format!(
+                    "label {label} is out of range for {num_classes} classes"
+                )))
+            }
+        }
+    }
+    Ok(counts
+        .into_iter()
+        .map(|count| match count {
+            0 => 0.0,
+            count => labels.len() as f32 / (num_classes * count) as f32,
+        })
+        .collect())
+}
+
+/// Draws the training rows of an epoch with replacement, every row with a
+/// probability inversely proportional to the frequency of its class, so on
+/// average every class fills the same share of a batch.
+#[derive(Debug, Clone)]
+pub struct WeightedSampler {
+    rows: WeightedIndex<f32>,
+    len: usize,
+}
+
+impl WeightedSampler {
+    /// Builds the sampler for the class labels `results`, which must lie in
+    /// `0..num_classes`.
+    pub fn new(results: &Tensor, num_classes: usize) -> Result<Self> {
+        let class_weights = inverse_frequency_weights(results, num_classes)?;
+        let labels = results.to_vec1::<u32>()?;
+        let rows = WeightedIndex::new(labels.iter().map(|&label| class_weights[label as usize]))
+            .map_err(|e| UtfRnnError::InvalidInput(format!("cannot sample the rows: {e}")))?;
+        Ok(Self {
+            rows,
+            len: labels.len(),
+        })
+    }
+
+    /// Draws the row indices of one epoch, as many as there are rows.
+    pub fn epoch_indices(&self, rng: &mut impl Rng) -> Vec<u32> {
+        (0..self.len)
+            .map(|_| self.rows.sample(rng) as u32)
+            .collect()
+    }
+}
+
+/// Stops training once the test loss has not improved by at least `min_delta`
+/// for `patience` consecutive epochs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EarlyStopping {
+    pub patience: usize,
+    pub min_delta: f32,
+}
+
+/// How the learning rate changes from epoch to epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum LrSchedule {
+    /// Keep the configured learning rate.
+    #[default]
+    Constant,
+    /// Multiply the learning rate by `gamma` every `step` epochs.
+    StepDecay { step: usize, gamma: f64 },
+    /// Anneal from the configured learning rate down to `min_lr` at the last
+    /// epoch along half a cosine wave.
+    Cosine { min_lr: f64 },
+}
+
+impl LrSchedule {
+    /// Learning rate for the 1-based `epoch` of an `epochs`-long run starting
+    /// at `base_lr`.
+    pub fn learning_rate(&self, base_lr: f64, epoch: usize, epochs: usize) -> f64 {
+        match *self {
+            Self::Constant => base_lr,
+            Self::StepDecay { step, gamma } => {
+                base_lr * gamma.powi(((epoch - 1) / step.max(1)) as i32)
+            }
+            Self::Cosine { min_lr } => {
+                if epochs <= 1 {
+                    return base_lr;
+                }
+                let progress = (epoch - 1) as f64 / (epochs - 1) as f64;
+                min_lr + 0.5 * (base_lr - min_lr) * (1.0 + (std::f64::consts::PI * progress).cos())
+            }
+        }
+    }
+}
+
+/// Hyperparameters used to build and train the perceptron. Fields missing
+/// from a config file keep their [`Default`] values.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrainConfig {
+    /// Number of vote columns of a model built or loaded from this config.
+    /// Training reads it from the dataset instead.
+    pub vote_dim: usize,
+    pub input_mode: InputMode,
+    pub task: Task,
+    /// Number of classes the output layer scores; ignored for regression,
+    /// which always has a single output.
+    pub num_classes: usize,
+    /// Output sizes of the hidden layers, from input to output.
+    pub hidden_sizes: Vec<usize>,
+    /// Activation applied after every hidden layer.
+    pub activation: Activation,
+    /// Give every layer a bias term; without it only the weights are learned.
+    pub bias: bool,
+    /// Distribution of the initial layer weights.
+    pub init: Init,
+    /// Probability of zeroing a hidden activation while training; `0.0`
+    /// disables dropout.
+    pub dropout: f32,
+    pub epochs: usize,
+    pub learning_rate: f64,
+    pub lr_schedule: LrSchedule,
+    /// Ramp the learning rate linearly up to `learning_rate` over this many
+    /// epochs before `lr_schedule` takes over for the remaining ones.
+    pub warmup_epochs: usize,
+    /// Base learning rates of the variables whose names start with each
+    /// prefix, e.g. `("ln3", 0.1)` for the output layer of the default
+    /// topology. The first matching prefix wins and every other variable uses
+    /// `learning_rate`; each group follows the warm-up and `lr_schedule` from
+    /// its own rate.
+    pub param_groups: Vec<(String, f64)>,
+    /// Layers, e.g. `ln1`, whose weights and biases the optimizer leaves
+    /// untouched; the forward pass still uses them.
+    pub frozen: Vec<String>,
+    pub optimizer: Optimizer,
+    pub loss: Loss,
+    /// Per-class multipliers of every sample's loss, e.g. from
+    /// [`inverse_frequency_weights`]; must have `num_classes` entries.
+    pub class_weights: Option<Vec<f32>>,
+    /// Share of every classification target, in `[0, 1)`, spread uniformly
+    /// over the classes instead of the label; `0.0` trains on one-hot
+    /// targets.
+    pub label_smoothing: f32,
+    /// Seed for the initial weights and the order of the training rows. With
+    /// a fixed seed and config without dropout, training on the CPU is
+    /// bit-for-bit reproducible; the dropout masks come from candle's CPU
+    /// RNG, which cannot be seeded, so `dropout > 0` varies between runs.
+    /// `None` draws fresh random weights.
+    pub seed: Option<u64>,
+    /// Seed for the initial weights (and the device RNG) only, overriding
+    /// `seed`, e.g. to vary the initialization over a fixed data order.
+    pub init_seed: Option<u64>,
+    /// Seed for the order of the training rows only, overriding `seed`: the
+    /// shuffles and the draws of balanced sampling.
+    pub data_seed: Option<u64>,
+    /// Emit `info` events for every attempt and epoch.
+    pub verbose: bool,
+    /// How `verbose` training reports every epoch.
+    pub metrics_format: MetricsFormat,
+    /// Also keep the test confusion matrix of every epoch, as
+    /// [`EpochMetrics::confusion`]; classification only.
+    pub track_confusion: bool,
+    /// Also score the training set after every epoch, as
+    /// [`EpochMetrics::train_accuracy`], at the cost of an extra forward pass.
+    pub compute_train_accuracy: bool,
+    /// Also emit the [`MultiLevelPerceptron::weight_stats`] of every epoch as
+    /// `info` events.
+    pub log_weight_stats: bool,
+    /// Stop once the test accuracy reaches `target_accuracy`; `false` always
+    /// runs all `epochs`.
+    pub stop_on_accuracy: bool,
+    /// Test accuracy, as a percentage in `(0, 100]`, that `train` and
+    /// `train_until_converged` require of a run, and that `stop_on_accuracy`
+    /// stops at.
+    pub target_accuracy: f32,
+    /// Never stop on accuracy before this many epochs.
+    pub min_epochs: usize,
+    /// Stop on a plateau of the validation loss (the test loss when the
+    /// dataset has no validation set) and restore the best weights seen.
+    pub early_stopping: Option<EarlyStopping>,
+    /// Train on shuffled mini-batches of this many rows instead of the full
+    /// training set at once.
+    pub batch_size: Option<usize>,
+    /// Shuffle the training rows every epoch, even without mini-batches.
+    pub shuffle: bool,
+    /// Draw the training rows of every epoch with a [`WeightedSampler`]
+    /// instead, oversampling the rare classes; classification only.
+    pub balanced_sampling: bool,
+    /// Standardize every input feature with the training-set mean and std.
+    pub normalize: bool,
+    /// Rescale the gradients so their global L2 norm is at most this value.
+    pub grad_clip: Option<f32>,
+    /// Average the gradients of this many mini-batches before every optimizer
+    /// step, emulating a batch that many times larger.
+    pub grad_accum_steps: usize,
+    /// Coefficient of an L2 penalty on the layer weights (not the biases)
+    /// added to the training loss; `0.0` disables it. Unlike AdamW's decay
+    /// this also applies to SGD.
+    pub weight_decay: f32,
+    /// Weight of the latest epoch in the moving average of the training loss
+    /// reported as [`EpochMetrics::train_loss_ema`], in `(0, 1]`; `1.0`
+    /// reports the raw loss. Does not affect the optimization.
+    pub ema_alpha: f32,
+    /// Write a checkpoint to `checkpoint_dir` after every this many epochs.
+    pub checkpoint_every: Option<usize>,
+    pub checkpoint_dir: PathBuf,
+    /// Factor, in `(0, 1]`, `train_until_converged` multiplies the learning
+    /// rates by after every failed attempt, including one whose loss
+    /// diverged; `1.0` retries at the same rates.
+    pub lr_backoff: f32,
+    /// Make `train_until_converged` return its most accurate attempt when
+    /// none reaches `target_accuracy`.
+    pub accept_best: bool,
+    /// Data type of the weights and votes, e.g. `BF16` or `F16` to halve the
+    /// memory use. The logits are upcast so the loss is always taken in `F32`.
+    #[serde(with = "dtype_name")]
+    pub dtype: DType,
+}
+
+/// (De)serializes a [`DType`] by its name, e.g. `"bf16"`.
+mod dtype_name {
+    use candle_core::DType;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(dtype: &DType, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(dtype.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse()
+            .map_err(|_| D::Error::custom(format!("unknown dtype {name:?}")))
+    }
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            vote_dim: VOTE_DIM,
+            input_mode: InputMode::default(),
+            task: Task::default(),
+            num_classes: RESULTS + 1,
+            hidden_sizes: vec![LAYER1_OUT_SIZE, LAYER2_OUT_SIZE],
+            activation: Activation::default(),
+            bias: true,
+            init: Init::default(),
+            dropout: 0.0,
+            epochs: EPOCHS,
+            learning_rate: LEARNING_RATE,
+            lr_schedule: LrSchedule::default(),
+            warmup_epochs: 0,
+            param_groups: Vec::new(),
+            frozen: Vec::new(),
+            optimizer: Optimizer::Sgd,
+            loss: Loss::default(),
+            class_weights: None,
+            label_smoothing: 0.0,
+            seed: None,
+            init_seed: None,
+            data_seed: None,
+            verbose: true,
+            metrics_format: MetricsFormat::default(),
+            track_confusion: false,
+            compute_train_accuracy: false,
+            log_weight_stats: false,
+            stop_on_accuracy: true,
+            target_accuracy: 100.0,
+            min_epochs: 0,
+            early_stopping: None,
+            batch_size: None,
+            shuffle: false,
+            balanced_sampling: false,
+            normalize: false,
+            grad_clip: None,
+            grad_accum_steps: 1,
+            weight_decay: 0.0,
+            ema_alpha: 0.1,
+            checkpoint_every: None,
+            checkpoint_dir: PathBuf::from("checkpoints"),
+            lr_backoff: 1.0,
+            accept_best: false,
+            dtype: DType::F32,
+        }
+    }
+}
+
+impl TrainConfig {
+    /// Seed of the initial weights: `init_seed`, falling back to `seed`.
+    fn weights_seed(&self) -> Option<u64> {
+        self.init_seed.or(self.seed)
+    }
+
+    /// Random number generator ordering the training rows, seeded with
+    /// `data_seed`, falling back to `seed`.
+    fn data_rng(&self) -> StdRng {
+        match self.data_seed.or(self.seed) {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    /// Reads a config from a `.toml` or `.json` file, chosen by the
+    /// extension of `path`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(toml::from_str(&text)?),
+            Some("json") => Ok(serde_json::from_str(&text)?),
+            _ => Err(UtfRnnError::InvalidInput(format!(
+                "{} is neither a .toml nor a .json file",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Learning rate of the 1-based `epoch`: `learning_rate * epoch /
+    /// warmup_epochs` during the warm-up, then `lr_schedule` restarted from
+    /// `learning_rate` over the epochs after it.
+    pub fn learning_rate_at(&self, epoch: usize) -> f64 {
+        self.group_learning_rate_at(self.learning_rate, epoch)
+    }
+
+    /// Like [`TrainConfig::learning_rate_at`], for a parameter group with the
+    /// base learning rate `base_lr`.
+    pub fn group_learning_rate_at(&self, base_lr: f64, epoch: usize) -> f64 {
+        let warmup = self.warmup_epochs;
+        if epoch <= warmup {
+            return base_lr * epoch as f64 / warmup as f64;
+        }
+        self.lr_schedule
+            .learning_rate(base_lr, epoch - warmup, self.epochs.saturating_sub(warmup))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Dataset {
+    pub train_votes: Tensor,
+    pub train_results: Tensor,
+    pub test_votes: Tensor,
+    pub test_results: Tensor,
+    /// Held-out `(votes, results)` watched by early stopping.
+    pub validation: Option<(Tensor, Tensor)>,
+}
+
+/// Disjoint train/validation/test partitions produced by `Dataset::split`.
+#[derive(Debug, Clone)]
+pub struct DatasetSplit {
+    pub train_votes: Tensor,
+    pub train_results: Tensor,
+    pub val_votes: Tensor,
+    pub val_results: Tensor,
+    pub test_votes: Tensor,
+    pub test_results: Tensor,
+}
+
+impl From<DatasetSplit> for Dataset {
+    fn from(split: DatasetSplit) -> Self {
+        Self {
+            train_votes: split.train_votes,
+            train_results: split.train_results,
+            test_votes: split.test_votes,
+            test_results: split.test_results,
+            validation: Some((split.val_votes, split.val_results)),
+        }
+    }
+}
+
+impl Dataset {
+    /// Number of vote columns, read from the training votes. The test and
+    /// validation votes must have the same width.
+    pub fn input_dim(&self) -> Result<usize> {
+        let (_, input_dim) = self.train_votes.dims2()?;
+        let mut others = vec![("test votes", &self.test_votes)];
+        if let Some((votes, _)) = &self.validation {
+            others.push(("validation votes", votes));
+        }
+        for (name, votes) in others {
+            if votes.rank() != 2 || votes.dim(1)? != input_dim {
+                return Err(UtfRnnError::ShapeMismatch {
+                    name: name.to_string(),
+                    expected: vec![votes.dims().first().copied().unwrap_or(0), input_dim],
+                    got: votes.dims().to_vec(),
+                });
+            }
+        }
+        Ok(input_dim)
+    }
+
+    /// Builds a dataset from flat, row-major vote counts `vote_dim` per row,
+    /// cast to `F32`, and one class label per row.
+    pub fn from_vote_counts(
+        train_votes: &[u32],
+        train_results: &[u32],
+        test_votes: &[u32],
+        test_results: &[u32],
+        vote_dim: usize,
+        dev: &Device,
+    ) -> Result<Self> {
+        let split = |name: &str, votes: &[u32], results: &[u32]| -> Result<(Tensor, Tensor)> {
+            let rows = results.len();
+            // An empty or ragged vector would be mis-shaped, or panic, below.
+            if votes.is_empty() || vote_dim == 0 || votes.len() != rows * vote_dim {
+                return Err(UtfRnnError::ShapeMismatch {
+                    name: format!("{name} votes"),
+                    expected: vec![rows.max(1), vote_dim],
+                    got: vec![votes.len()],
+                });
+            }
+            Ok((
+                Tensor::from_slice(votes, (rows, vote_dim), dev)?.to_dtype(DType::F32)?,
+                Tensor::from_slice(results, rows, dev)?,
+            ))
+        };
+        let (train_votes, train_results) = split("train", train_votes, train_results)?;
+        let (test_votes, test_results) = split("test", test_votes, test_results)?;
+        Ok(Self {
+            train_votes,
+            train_results,
+            test_votes,
+            test_results,
+            validation: None,
+        })
+    }
+
+    /// Builds a dataset from plain rows of votes and one class label per
+    /// row, keeping the first `DEFAULT_TRAIN_RATIO` of them for training and
+    /// at least one for testing.
+    pub fn from_rows(rows: &[Vec<f32>], labels: &[u32], dev: &Device) -> Result<Self> {
+        if rows.len() < 2 || labels.len() != rows.len() {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "need at least two rows with one label each, got {} rows and {} labels",
+                rows.len(),
+                labels.len()
+            )));
+        }
+        let vote_dim = rows[0].len();
+        if let Some(row) = rows
+            .iter()
+            .find(|row| row.is_empty() || row.len() != vote_dim)
+        {
+            return Err(UtfRnnError::ShapeMismatch {
+                name: "vote row".to_string(),
+                expected: vec![vote_dim.max(1)],
+                got: vec![row.len()],
+            });
+        }
+
+        let n = rows.len();
+        let train_len = ((n as f32 * DEFAULT_TRAIN_RATIO).round() as usize).clamp(1, n - 1);
+        let votes = Tensor::from_vec(rows.concat(), (n, vote_dim), dev)?;
+        let results = Tensor::from_slice(labels, n, dev)?;
+        Ok(Self {
+            train_votes: votes.narrow(0, 0, train_len)?,
+            train_results: results.narrow(0, 0, train_len)?,
+            test_votes: votes.narrow(0, train_len, n - train_len)?,
+            test_results: results.narrow(0, train_len, n - train_len)?,
+            validation: None,
+        })
+    }
+
+    /// Reads a text corpus and cuts it into next-character windows for
+    /// `CharRnn`, keeping the first `DEFAULT_TRAIN_RATIO` of them for
+    /// training.
+    pub fn from_text_file(path: &Path, seq_len: usize, dev: &Device) -> Result<(Self, CharVocab)> {
+        Self::from_text_file_with_ratio(path, seq_len, DEFAULT_TRAIN_RATIO, dev)
+    }
+
+    /// Like `from_text_file`, with the share of training windows given by
+    /// `train_ratio`. Only a ratio of `1.0` leaves the test set empty.
+    ///
+    /// Every window of `seq_len` codepoint ids becomes a `votes` row and the
+    /// same window shifted by one character becomes its `results` row, both
+    /// shaped `(windows, seq_len)`.
+    pub fn from_text_file_with_ratio(
+        path: &Path,
+        seq_len: usize,
+        train_ratio: f32,
+        dev: &Device,
+    ) -> Result<(Self, CharVocab)> {
+        let text = fs::read_to_string(path)?;
+        if text.is_empty() {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "{} is empty",
+                path.display()
+            )));
+        }
+        if seq_len == 0 {
+            return Err(UtfRnnError::InvalidInput(
+                "seq_len must be at least 1".to_string(),
+            ));
+        }
+        if !(train_ratio > 0.0 && train_ratio <= 1.0) {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "train_ratio must be in (0, 1], got {train_ratio}"
+            )));
+        }
+
+        let vocab = CharVocab::new(&text);
+        let ids = vocab.encode(&text);
+        // Each window needs one extra character for its shifted target
+        if seq_len >= ids.len() {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "seq_len {seq_len} needs a corpus of at least {} characters, {} has {}",
+                seq_len + 1,
+                path.display(),
+                ids.len()
+            )));
+        }
+
+        let windows = ids.len() - seq_len;
+        // Below a ratio of one, at least one window is kept for testing.
+        let train_len = if train_ratio < 1.0 {
+            if windows < 2 {
+                return Err(UtfRnnError::InvalidInput(format!(
+                    "{} has {windows} window of seq_len {seq_len}, at least two are needed to train and test",
+                    path.display()
+                )));
+            }
+            ((windows as f32 * train_ratio).round() as usize).clamp(1, windows - 1)
+        } else {
+            windows
+        };
+        let to_tensors = |range: std::ops::Range<usize>| -> Result<(Tensor, Tensor)> {
+            let rows = range.len();
+            let inputs: Vec<u32> = range
+                .clone()
+                .flat_map(|i| ids[i..i + seq_len].iter().copied())
+                .collect();
+            let targets: Vec<u32> = range
+                .flat_map(|i| ids[i + 1..i + 1 + seq_len].iter().copied())
+                .collect();
+            Ok((
+                Tensor::from_vec(inputs, (rows, seq_len), dev)?,
+                Tensor::from_vec(targets, (rows, seq_len), dev)?,
+            ))
+        };
+        let (train_votes, train_results) = to_tensors(0..train_len)?;
+        let (test_votes, test_results) = to_tensors(train_len..windows)?;
+
+        Ok((
+            Self {
+                train_votes,
+                train_results,
+                test_votes,
+                test_results,
+                validation: None,
+            },
+            vocab,
+        ))
+    }
+
+    /// Reads a numeric CSV file, taking `feature_cols` as the votes and
+    /// `label_col` as the class label, and keeps the first
+    /// `DEFAULT_TRAIN_RATIO` of the rows for training.
+    pub fn from_csv(
+        path: &Path,
+        feature_cols: &[usize],
+        label_col: usize,
+        dev: &Device,
+    ) -> Result<Self> {
+        Self::from_csv_with_ratio(path, feature_cols, label_col, DEFAULT_TRAIN_RATIO, dev)
+    }
+
+    /// Like `from_csv`, with the share of training rows given by
+    /// `train_ratio`. At least one row is always kept for testing.
+    ///
+    /// A first line that does not parse as numbers is treated as a header.
+    /// Feature columns may mix integer counts and floats; all are read as
+    /// `F32` and cast to the training dtype by `fit`. An empty, `NA` or `NaN`
+    /// cell is an error naming its line. The votes are shaped `(rows,
+    /// feature_cols.len())`.
+    pub fn from_csv_with_ratio(
+        path: &Path,
+        feature_cols: &[usize],
+        label_col: usize,
+        train_ratio: f32,
+        dev: &Device,
+    ) -> Result<Self> {
+        if feature_cols.is_empty() {
+            return Err(UtfRnnError::InvalidInput(
+                "at least one feature column is required".to_string(),
+            ));
+        }
+        if !(train_ratio > 0.0 && train_ratio < 1.0) {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "train_ratio must be in (0, 1), got {train_ratio}"
+            )));
+        }
+
+        let text = fs::read_to_string(path)?;
+        let mut votes: Vec<f32> = Vec::new();
+        let mut labels: Vec<u32> = Vec::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let error = |msg: String| {
+                UtfRnnError::InvalidInput(format!("{}:{}: {msg}", path.display(), line_idx + 1))
+            };
+            let field = |col: usize| {
+                fields
+                    .get(col)
+                    .copied()
+                    .ok_or_else(|| error(format!("missing column {col}")))
+            };
+
+            // A header has no empty cells, so a row with one is never skipped.
+            let has_missing = feature_cols
+                .iter()
+                .chain([&label_col])
+                .any(|&col| fields.get(col).is_some_and(|value| is_missing(value)));
+
+            let row: Result<Vec<f32>> = feature_cols
+                .iter()
+                .map(|&col| {
+                    let value = field(col)?;
+                    if is_missing(value) {
+                        return Err(error(format!("missing value in column {col}")));
+                    }
+                    value
+                        .parse::<f32>()
+                        .map_err(|e| error(format!("invalid value {value:?} in column {col}: {e}")))
+                })
+                .collect();
+            let label = field(label_col).and_then(|value| {
+                if is_missing(value) {
+                    return Err(error("missing label".to_string()));
+                }
+                value
+                    .parse::<u32>()
+                    .map_err(|e| error(format!("invalid label {value:?}: {e}")))
+            });
+            match (row, label) {
+                (Ok(row), Ok(label)) => {
+                    votes.extend(row);
+                    labels.push(label);
+                }
+                // Skip a header line
+                _ if line_idx == 0 && !has_missing => continue,
+                (Err(e), _) | (_, Err(e)) => return Err(e),
+            }
+        }
+        if labels.len() < 2 {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "{} has {} data rows, at least two are needed to train and test",
+                path.display(),
+                labels.len()
+            )));
+        }
+
+        let vote_dim = feature_cols.len();
+        let rows = labels.len();
+        let train_len = ((rows as f32 * train_ratio).round() as usize).clamp(1, rows - 1);
+        let votes = Tensor::from_vec(votes, (rows, vote_dim), dev)?;
+        let results = Tensor::from_vec(labels, rows, dev)?;
+
+        Ok(Self {
+            train_votes: votes.narrow(0, 0, train_len)?,
+            train_results: results.narrow(0, 0, train_len)?,
+            test_votes: votes.narrow(0, train_len, rows - train_len)?,
+            test_results: results.narrow(0, train_len, rows - train_len)?,
+            validation: None,
+        })
+    }
+
+    /// Generates `n` random rows of `dim` votes, uniform in `[-1, 1]`, labelled
+    /// `1` when their votes sum to more than zero and `0` otherwise, and keeps
+    /// the first `DEFAULT_TRAIN_RATIO` of them for training. Rows within `0.1`
+    /// of that boundary are redrawn, so the classes are separated by a
+    /// margin. The same `seed` always gives the same dataset.
+    pub fn synthetic_linearly_separable(
+        n: usize,
+        dim: usize,
+        seed: u64,
+        dev: &Device,
+    ) -> Result<Self> {
+        if n < 2 || dim == 0 {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "a synthetic dataset needs at least two rows and one column, got {n}x{dim}"
+            )));
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        // Distance of a row from the boundary `sum(x) = 0`.
+        let distance = |row: &[f32]| row.iter().sum::<f32>() / (dim as f32).sqrt();
+        let mut votes = Vec::with_capacity(n * dim);
+        let mut labels = Vec::with_capacity(n);
+        while labels.len() < n {
+            let row: Vec<f32> = (0..dim).map(|_| rng.gen_range(-1.0..=1.0)).collect();
+            let distance = distance(&row);
+            if distance.abs() < 0.1 {
+                continue;
+            }
+            labels.push(u32::from(distance > 0.0));
+            votes.extend(row);
+        }
+
+        let train_len = ((n as f32 * DEFAULT_TRAIN_RATIO).round() as usize).clamp(1, n - 1);
+        let votes = Tensor::from_vec(votes, (n, dim), dev)?;
+        let results = Tensor::from_vec(labels, n, dev)?;
+        Ok(Self {
+            train_votes: votes.narrow(0, 0, train_len)?,
+            train_results: results.narrow(0, 0, train_len)?,
+            test_votes: votes.narrow(0, train_len, n - train_len)?,
+            test_results: results.narrow(0, train_len, n - train_len)?,
+            validation: None,
+        })
+    }
+
+    /// Shuffles the rows of `votes` and `labels` with `seed` and partitions
+    /// them into train, validation and test sets by `ratios`, which must sum
+    /// to one.
+    pub fn split(
+        votes: Tensor,
+        labels: Tensor,
+        ratios: (f32, f32, f32),
+        seed: u64,
+    ) -> Result<DatasetSplit> {
+        let (train_ratio, val_ratio, test_ratio) = ratios;
+        if [train_ratio, val_ratio, test_ratio]
+            .iter()
+            .any(|r| *r < 0.0)
+            || (train_ratio + val_ratio + test_ratio - 1.0).abs() > 1e-4
+        {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "split ratios must be non-negative and sum to 1, got {ratios:?}"
+            )));
+        }
+        let rows = votes.dim(0)?;
+        if labels.dim(0)? != rows {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "votes have {rows} rows but labels have {}",
+                labels.dim(0)?
+            )));
+        }
+
+        let mut order: Vec<u32> = (0..rows as u32).collect();
+        order.shuffle(&mut StdRng::seed_from_u64(seed));
+        let train_len = (rows as f32 * train_ratio).round() as usize;
+        let val_len = ((rows as f32 * val_ratio).round() as usize).min(rows - train_len);
+
+        let dev = votes.device();
+        let take = |start: usize, len: usize| -> Result<(Tensor, Tensor)> {
+            let idx = Tensor::from_slice(&order[start..start + len], len, dev)?;
+            Ok((votes.index_select(&idx, 0)?, labels.index_select(&idx, 0)?))
+        };
+        let (train_votes, train_results) = take(0, train_len)?;
+        let (val_votes, val_results) = take(train_len, val_len)?;
+        let (test_votes, test_results) = take(train_len + val_len, rows - train_len - val_len)?;
+
+        Ok(DatasetSplit {
+            train_votes,
+            train_results,
+            val_votes,
+            val_results,
+            test_votes,
+            test_results,
+        })
+    }
+}
+
+/// Per-feature statistics mapping votes to `(x - mean) / std`.
+#[derive(Debug, Clone)]
+pub struct Standardizer {
+    /// `(1, vote_dim)` mean of every feature.
+    pub mean: Tensor,
+    /// `(1, vote_dim)` population standard deviation of every feature.
+    pub std: Tensor,
+}
+
+impl Standardizer {
+    /// Computes the statistics of the `(n, vote_dim)` tensor `votes`.
+    pub fn fit(votes: &Tensor) -> Result<Self> {
+        let mean = votes.mean_keepdim(0)?;
+        let var = votes.broadcast_sub(&mean)?.sqr()?.mean_keepdim(0)?;
+        // Keep constant features from dividing by zero.
+        let std = var.sqrt()?.maximum(1e-6)?;
+        Ok(Self { mean, std })
+    }
+
+    pub fn transform(&self, votes: &Tensor) -> Result<Tensor> {
+        Ok(votes.broadcast_sub(&self.mean)?.broadcast_div(&self.std)?)
+    }
+}
+
+/// Summary statistics of the values of one weight or bias tensor.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerStats {
+    /// Variable name, e.g. `ln1.weight`.
+    pub name: String,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Population standard deviation.
+    pub std: f32,
+}
+
+impl LayerStats {
+    fn new(name: String, tensor: &Tensor) -> Result<Self> {
+        let values = tensor
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let n = values.len() as f32;
+        let mean = values.iter().sum::<f32>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+        Ok(Self {
+            name,
+            min: values.iter().copied().fold(f32::INFINITY, f32::min),
+            max: values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            mean,
+            std: variance.sqrt(),
+        })
+    }
+}
+
+pub struct MultiLevelPerceptron {
+    /// Number of vote columns every input row must have.
+    input_dim: usize,
+    /// Embedding of the category ids in [`InputMode::Categorical`].
+    embedding: Option<Embedding>,
+    /// Linear layers named `ln1`, `ln2`, ... with the activation between them.
+    layers: Vec<Linear>,
+    activation: Activation,
+    /// Distribution the weights were drawn from, reused by `reinit`.
+    init: Init,
+    /// Dropout applied after every hidden activation in training mode.
+    dropout: Option<Dropout>,
+    /// Input standardization learned from the training votes, applied before
+    /// the first layer. Not part of the saved weights.
+    standardizer: Option<Standardizer>,
+}
+
+/// Chainable constructor of a [`MultiLevelPerceptron`], validating the layer
+/// sizes before any weights are created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerceptronBuilder {
+    input_dim: usize,
+    input_mode: InputMode,
+    hidden_sizes: Vec<usize>,
+    num_classes: usize,
+    activation: Activation,
+    bias: bool,
+    init: Init,
+    dropout: f32,
+}
+
+impl Default for PerceptronBuilder {
+    fn default() -> Self {
+        Self {
+            input_dim: VOTE_DIM,
+            input_mode: InputMode::default(),
+            hidden_sizes: vec![LAYER1_OUT_SIZE, LAYER2_OUT_SIZE],
+            num_classes: RESULTS + 1,
+            activation: Activation::default(),
+            bias: true,
+            init: Init::default(),
+            dropout: 0.0,
+        }
+    }
+}
+
+impl PerceptronBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from the architecture described by `config`.
+    pub fn from_config(config: &TrainConfig) -> Self {
+        Self {
+            input_dim: config.vote_dim,
+            input_mode: config.input_mode,
+            hidden_sizes: config.hidden_sizes.clone(),
+            num_classes: match config.task {
+                Task::Classification => config.num_classes,
+                Task::Regression => 1,
+                Task::MultiLabel { num_labels } => num_labels,
+            },
+            activation: config.activation,
+            bias: config.bias,
+            init: config.init,
+            dropout: config.dropout,
+        }
+    }
+
+    /// Number of vote columns of every input row.
+    pub fn input_dim(mut self, input_dim: usize) -> Self {
+        self.input_dim = input_dim;
+        self
+    }
+
+    pub fn input_mode(mut self, input_mode: InputMode) -> Self {
+        self.input_mode = input_mode;
+        self
+    }
+
+    /// Output sizes of the hidden layers, from input to output.
+    pub fn hidden(mut self, hidden_sizes: Vec<usize>) -> Self {
+        self.hidden_sizes = hidden_sizes;
+        self
+    }
+
+    pub fn num_classes(mut self, num_classes: usize) -> Self {
+        self.num_classes = num_classes;
+        self
+    }
+
+    pub fn activation(mut self, activation: Activation) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    /// Whether the layers have bias terms.
+    pub fn bias(mut self, bias: bool) -> Self {
+        self.bias = bias;
+        self
+    }
+
+    /// Distribution of the initial layer weights.
+    pub fn init(mut self, init: Init) -> Self {
+        self.init = init;
+        self
+    }
+
+    /// Probability of zeroing a hidden activation while training.
+    pub fn dropout(mut self, dropout: f32) -> Self {
+        self.dropout = dropout;
+        self
+    }
+
+    /// Checks the sizes and creates the layers' weights in `vs`.
+    pub fn build(self, vs: VarBuilder) -> Result<MultiLevelPerceptron> {
+        if self.input_dim == 0 || self.num_classes == 0 || self.hidden_sizes.contains(&0) {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "layer sizes must be positive: input {}, hidden {:?}, classes {}",
+                self.input_dim, self.hidden_sizes, self.num_classes
+            )));
+        }
+        if !(0.0..1.0).contains(&self.dropout) {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "dropout must be in [0, 1), got {}",
+                self.dropout
+            )));
+        }
+
+        // Categorical inputs reach the first layer as concatenated embeddings.
+        let (embedding, input_size) = match self.input_mode {
+            InputMode::Float => (None, self.input_dim),
+            InputMode::Categorical {
+                vocab_size,
+                embed_dim,
+            } => (
+                Some(candle_nn::embedding(
+                    vocab_size,
+                    embed_dim,
+                    vs.pp("embedding"),
+                )?),
+                self.input_dim * embed_dim,
+            ),
+        };
+        // Input, hidden and output sizes, so each window is one layer's (in, out).
+        let mut sizes = vec![input_size];
+        sizes.extend(&self.hidden_sizes);
+        sizes.push(self.num_classes);
+
+        let mut layers = Vec::with_capacity(sizes.len() - 1);
+        for (i, dims) in sizes.windows(2).enumerate() {
+            let vs = vs.pp(format!("ln{}", i + 1));
+            let (fan_in, fan_out) = (dims[0], dims[1]);
+            let weight = vs.get_with_hints(
+                (fan_out, fan_in),
+                "weight",
+                self.init.weight_init(fan_in, fan_out),
+            )?;
+            let bias = if self.bias {
+                let bound = 1. / (fan_in as f64).sqrt();
+                let init = candle_nn::Init::Uniform {
+                    lo: -bound,
+                    up: bound,
+                };
+                Some(vs.get_with_hints(fan_out, "bias", init)?)
+            } else {
+                None
+            };
+            layers.push(Linear::new(weight, bias));
+        }
+        Ok(MultiLevelPerceptron {
+            input_dim: self.input_dim,
+            embedding,
+            layers,
+            activation: self.activation,
+            init: self.init,
+            dropout: (self.dropout > 0.0).then(|| Dropout::new(self.dropout)),
+            standardizer: None,
+        })
+    }
+}
+
+impl MultiLevelPerceptron {
+    /// Builds the architecture described by `config`; see
+    /// [`PerceptronBuilder`] to set the sizes one by one.
+    pub fn new(vs: VarBuilder, config: &TrainConfig) -> Result<Self> {
+        PerceptronBuilder::from_config(config).build(vs)
+    }
+
+    /// Runs the model in evaluation mode, with dropout disabled.
+    pub fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.forward_t(xs, false)
+    }
+
+    /// Runs the model, applying dropout only when `train` is set. Float votes
+    /// are cast to the dtype of the weights and categorical ones to `U32`
+    /// ids; the logits are returned as `F32` whatever the weights' dtype is.
+    pub fn forward_t(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
+        // Catch a wrong number of vote columns before it fails deep in a matmul.
+        if xs.rank() != 2 || xs.dim(1)? != self.input_dim {
+            return Err(UtfRnnError::ShapeMismatch {
+                name: "input votes".to_string(),
+                expected: vec![xs.dims().first().copied().unwrap_or(0), self.input_dim],
+                got: xs.dims().to_vec(),
+            });
+        }
+        let (last, hidden) = self.layers.split_last().expect("at least one layer");
+        let mut xs = match &self.embedding {
+            // (n, vote_dim) ids -> (n, vote_dim, embed_dim) -> (n, vote_dim * embed_dim)
+            Some(embedding) => embedding
+                .forward(&xs.to_dtype(DType::U32)?)?
+                .flatten_from(1)?,
+            None => {
+                let xs = xs.to_dtype(self.layers[0].weight().dtype())?;
+                match &self.standardizer {
+                    Some(standardizer) => standardizer.transform(&xs)?,
+                    None => xs,
+                }
+            }
+        };
+        for layer in hidden {
+            xs = apply_activation(&layer.forward(&xs)?, self.activation)?;
+            if let Some(dropout) = &self.dropout {
+                xs = dropout.forward(&xs, train)?;
+            }
+        }
+        Ok(last.forward(&xs)?.to_dtype(DType::F32)?)
+    }
+
+    /// Returns the unnormalized `(n, num_classes)` scores of the `(n,
+    /// vote_dim)` tensor `votes`, before any softmax or argmax.
+    ///
+    /// The pass runs on detached copies of the weights, biases, embedding
+    /// and standardizer, so none of its tensors track a gradient and no
+    /// autograd graph is built: the `predict*` methods built on it never
+    /// grow memory however often they are called. Train through
+    /// [`MultiLevelPerceptron::forward_t`] instead.
+    pub fn logits(&self, votes: &Tensor) -> Result<Tensor> {
+        self.detached()?.forward(votes)
+    }
+
+    /// A copy of the model sharing its parameters and standardizer, detached
+    /// from the autograd graph. No data is copied.
+    fn detached(&self) -> Result<Self> {
+        let embedding = match &self.embedding {
+            Some(embedding) => {
+                let embeddings = embedding.embeddings().detach();
+                let hidden_size = embeddings.dim(1)?;
+                Some(Embedding::new(embeddings, hidden_size))
+            }
+            None => None,
+        };
+        Ok(Self {
+            input_dim: self.input_dim,
+            embedding,
+            layers: self
+                .layers
+                .iter()
+                .map(|layer| Linear::new(layer.weight().detach(), layer.bias().map(Tensor::detach)))
+                .collect(),
+            activation: self.activation,
+            init: self.init,
+            dropout: self.dropout.clone(),
+            standardizer: self.standardizer.as_ref().map(|standardizer| Standardizer {
+                mean: standardizer.mean.detach(),
+                std: standardizer.std.detach(),
+            }),
+        })
+    }
+
+    /// Redraws the weights and biases held in `varmap`, which must be the
+    /// map the model was built from, from an RNG seeded with `seed`, the way
+    /// `TrainConfig::seed` initializes them. The tensors are overwritten in
+    /// place, so a retry can start afresh without building a new model; the
+    /// standardizer is kept.
+    pub fn reinit(&mut self, varmap: &mut VarMap, seed: u64) -> Result<()> {
+        init_seeded(varmap, seed, self.init)
+    }
+
+    /// Predicts a class index for every row of `votes`, which must have shape
+    /// `(n, vote_dim)`: the argmax of [`MultiLevelPerceptron::logits`].
+    pub fn predict(&self, votes: &Tensor) -> Result<Vec<u32>> {
+        let logits = self.logits(votes)?;
+        Ok(logits.argmax(D::Minus1)?.to_vec1::<u32>()?)
+    }
+
+    /// Returns the output of a regression model for every row of `votes`.
+    pub fn predict_values(&self, votes: &Tensor) -> Result<Vec<f32>> {
+        let outputs = self.num_classes()?;
+        if outputs != 1 {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "predict_values needs a regression model with one output, this one has {outputs}"
+            )));
+        }
+        Ok(self.logits(votes)?.squeeze(1)?.to_vec1::<f32>()?)
+    }
+
+    /// Returns, for every row of `votes`, which labels of a multi-label model
+    /// have a sigmoid probability above `0.5`.
+    pub fn predict_labels(&self, votes: &Tensor) -> Result<Vec<Vec<bool>>> {
+        // sigmoid(x) > 0.5 exactly when x > 0.
+        let positive = self.logits(votes)?.gt(0f32)?.to_vec2::<u8>()?;
+        Ok(positive
+            .into_iter()
+            .map(|row| row.into_iter().map(|p| p == 1).collect())
+            .collect())
+    }
+
+    /// Number of vote columns every input row must have.
+    pub fn input_dim(&self) -> usize {
+        self.input_dim
+    }
+
+    /// Number of classes scored by the output layer.
+    fn num_classes(&self) -> Result<usize> {
+        let last = self.layers.last().expect("at least one layer");
+        Ok(last.weight().dim(0)?)
+    }
+
+    /// Total number of trainable values: every weight and bias, plus the
+    /// embedding table of categorical inputs.
+    pub fn num_parameters(&self) -> usize {
+        let embedding = self
+            .embedding
+            .as_ref()
+            .map_or(0, |embedding| embedding.embeddings().elem_count());
+        let layers: usize = self
+            .layers
+            .iter()
+            .map(|layer| layer.weight().elem_count() + layer.bias().map_or(0, |b| b.elem_count()))
+            .sum();
+        embedding + layers
+    }
+
+    /// One line per layer with its input and output sizes and parameter
+    /// count, followed by the total.
+    pub fn summary(&self) -> String {
+        let mut lines = Vec::with_capacity(self.layers.len() + 2);
+        if let Some(embedding) = &self.embedding {
+            let (vocab_size, embed_dim) = embedding.embeddings().dims2().unwrap_or((0, 0));
+            lines.push(format!(
+                "embedding: {vocab_size} ids -> {embed_dim} ({} params)",
+                vocab_size * embed_dim
+            ));
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            let (out_dim, in_dim) = layer.weight().dims2().unwrap_or((0, 0));
+            let params = layer.weight().elem_count() + layer.bias().map_or(0, |b| b.elem_count());
+            lines.push(format!(
+                "ln{}: {in_dim} -> {out_dim} ({params} params)",
+                i + 1
+            ));
+        }
+        lines.push(format!("total: {} params", self.num_parameters()));
+        lines.join("\n")
+    }
+
+    /// Statistics of every weight and bias tensor, in layer order, starting
+    /// with the embedding table of categorical inputs.
+    pub fn weight_stats(&self) -> Result<Vec<LayerStats>> {
+        let mut stats = Vec::with_capacity(2 * self.layers.len() + 1);
+        if let Some(embedding) = &self.embedding {
+            stats.push(LayerStats::new(
+                "embedding.weight".to_string(),
+                embedding.embeddings(),
+            )?);
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            stats.push(LayerStats::new(
+                format!("ln{}.weight", i + 1),
+                layer.weight(),
+            )?);
+            if let Some(bias) = layer.bias() {
+                stats.push(LayerStats::new(format!("ln{}.bias", i + 1), bias)?);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns the softmax class probabilities for every row of the `(n,
+    /// vote_dim)` tensor `votes` as an `(n, num_classes)` tensor: the softmax
+    /// of [`MultiLevelPerceptron::logits`].
+    pub fn predict_proba(&self, votes: &Tensor) -> Result<Tensor> {
+        let logits = self.logits(votes)?;
+        Ok(ops::softmax(&logits, D::Minus1)?)
+    }
+
+    /// Returns, for every row of `votes`, the `k` most likely classes with
+    /// their softmax probabilities, most likely first. `k` is capped at the
+    /// number of classes.
+    pub fn predict_topk(&self, votes: &Tensor, k: usize) -> Result<Vec<Vec<(u32, f32)>>> {
+        let probabilities = self.predict_proba(votes)?.to_vec2::<f32>()?;
+        let by_probability = |a: &(u32, f32), b: &(u32, f32)| b.1.total_cmp(&a.1);
+        Ok(probabilities
+            .into_iter()
+            .map(|row| {
+                let mut ranked: Vec<(u32, f32)> = row
+                    .into_iter()
+                    .enumerate()
+                    .map(|(class, p)| (class as u32, p))
+                    .collect();
+                let k = k.min(ranked.len());
+                if k == 0 {
+                    return Vec::new();
+                }
+                // Only the first k entries need to be ordered.
+                ranked.select_nth_unstable_by(k - 1, by_probability);
+                ranked.truncate(k);
+                ranked.sort_unstable_by(by_probability);
+                ranked
+            })
+            .collect())
+    }
+
+    /// Importance of every vote of the `(n, vote_dim)` float tensor `input` to
+    /// its row's prediction: the absolute gradient of the predicted class's
+    /// logit with respect to the vote, as an `(n, vote_dim)` `F32` tensor.
+    pub fn saliency(&self, input: &Tensor) -> Result<Tensor> {
+        if self.embedding.is_some() {
+            return Err(UtfRnnError::InvalidInput(
+                "saliency needs float inputs, not category ids".to_string(),
+            ));
+        }
+        let weights = self.layers[0].weight();
+        let input = Var::from_tensor(
+            &input
+                .to_device(weights.device())?
+                .to_dtype(weights.dtype())?,
+        )?;
+        // Not `logits`, which detaches the scores from the votes.
+        let logits = self.forward(input.as_tensor())?;
+        let predicted = logits.argmax_keepdim(D::Minus1)?;
+        let grads = logits.gather(&predicted, 1)?.sum_all()?.backward()?;
+        let grad = match grads.get(&input) {
+            Some(grad) => grad.clone(),
+            // No path from the votes to the logits, e.g. behind dead ReLUs.
+            None => input.as_tensor().zeros_like()?,
+        };
+        Ok(grad.abs()?.to_dtype(DType::F32)?)
+    }
+
+    /// Scores the model on the test votes and results of `dataset`, e.g. a
+    /// freshly loaded one, without training or changing anything. Only
+    /// classifiers with one `U32` class label per row can be scored;
+    /// regression and multi-label models are rejected.
+    pub fn score(&self, dataset: &Dataset) -> Result<EvalReport> {
+        let outputs = self.num_classes()?;
+        let labels = &dataset.test_results;
+        if outputs < 2 || labels.rank() != 1 || labels.dtype() != DType::U32 {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "score needs a classifier and one U32 class label per row, \
+                 got {outputs} outputs and {:?} labels of shape {:?}",
+                labels.dtype(),
+                labels.dims()
+            )));
+        }
+        let device = self.layers[0].weight().device();
+        let votes = dataset.test_votes.to_device(device)?;
+        let labels = labels.to_device(device)?;
+        let loss = Loss::default()
+            .compute(&self.logits(&votes)?, &labels)?
+            .to_scalar::<f32>()?;
+        let confusion = evaluate(self, &votes, &labels)?;
+        Ok(EvalReport {
+            accuracy: 100. * confusion.accuracy(),
+            loss,
+            confusion,
+        })
+    }
+
+    /// Writes the weights held in `varmap` to a `.safetensors` file at `path`,
+    /// together with the standardizer statistics, if any, so a reloaded
+    /// model transforms raw votes the same way.
+    pub fn save(&self, varmap: &VarMap, path: &Path) -> Result<()> {
+        let mut tensors: HashMap<String, Tensor> = varmap
+            .data()
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+            .collect();
+        if let Some(standardizer) = &self.standardizer {
+            tensors.insert(STANDARDIZER_MEAN.to_string(), standardizer.mean.clone());
+            tensors.insert(STANDARDIZER_STD.to_string(), standardizer.std.clone());
+        }
+        candle_core::safetensors::save(&tensors, path)?;
+        Ok(())
+    }
+
+    /// Restores a model written by [`MultiLevelPerceptron::save`], including
+    /// its standardizer. The layer sizes in `config` must match the ones the
+    /// weights were saved with.
+    pub fn load(path: &Path, dev: &Device, config: &TrainConfig) -> Result<Self> {
+        let varmap = VarMap::new();
+        let mut model = Self::new(VarBuilder::from_varmap(&varmap, config.dtype, dev), config)?;
+        load_weights(&varmap, path, dev)?;
+        let tensors = candle_core::safetensors::load(path, dev)?;
+        if let (Some(mean), Some(std)) = (
+            tensors.get(STANDARDIZER_MEAN),
+            tensors.get(STANDARDIZER_STD),
+        ) {
+            model.standardizer = Some(Standardizer {
+                mean: mean.clone(),
+                std: std.clone(),
+            });
+        }
+        Ok(model)
+    }
+
+    /// Predicts every row of `rows` in a single forward pass, returning each
+    /// row's class together with its softmax probability.
+    pub fn predict_batch(&self, rows: &[impl AsRef<[f32]>]) -> Result<Vec<(u32, f32)>> {
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let device = self.layers[0].weight().device();
+        if let Some(row) = rows.iter().find(|row| row.as_ref().len() != self.input_dim) {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "every row must have {} votes, got {:?}",
+                self.input_dim,
+                row.as_ref()
+            )));
+        }
+        let values: Vec<f32> = rows
+            .iter()
+            .flat_map(|row| row.as_ref().iter().copied())
+            .collect();
+        let votes = Tensor::from_vec(values, (rows.len(), self.input_dim), device)?;
+        let probabilities = self.predict_proba(&votes)?;
+        let classes = probabilities.argmax(D::Minus1)?.to_vec1::<u32>()?;
+        let confidences = probabilities.max(D::Minus1)?.to_vec1::<f32>()?;
+        Ok(classes.into_iter().zip(confidences).collect())
+    }
+
+    /// Like [`MultiLevelPerceptron::predict`], but abstains with `None` on
+    /// every row whose highest softmax probability is below `min_conf`, e.g.
+    /// to hand it to a human instead.
+    pub fn predict_with_threshold(
+        &self,
+        votes: &Tensor,
+        min_conf: f32,
+    ) -> Result<Vec<Option<u32>>> {
+        let probabilities = self.predict_proba(votes)?;
+        let classes = probabilities.argmax(D::Minus1)?.to_vec1::<u32>()?;
+        let confidences = probabilities.max(D::Minus1)?.to_vec1::<f32>()?;
+        Ok(classes
+            .into_iter()
+            .zip(confidences)
+            .map(|(class, confidence)| (confidence >= min_conf).then_some(class))
+            .collect())
+    }
+
+    /// Predicts the class of a single row of votes.
+    pub fn predict_one(&self, votes: &[f32]) -> Result<u32> {
+        let device = self.layers[0].weight().device();
+        let votes = Tensor::from_slice(votes, (1, votes.len()), device)?;
+        Ok(self.predict(&votes)?[0])
+    }
+
+    /// Predicts the outcome of a single row of votes with a binary model,
+    /// such as the one trained by the demo.
+    pub fn predict_outcome(&self, votes: &[f32]) -> Result<VoteOutcome> {
+        let num_classes = self.num_classes()?;
+        if num_classes != 2 {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "predict_outcome needs a binary model, this one has {num_classes} classes"
+            )));
+        }
+        VoteOutcome::try_from(self.predict_one(votes)?)
+    }
+}
+
+/// Prediction of a binary vote model: class `1` passes, class `0` rejects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VoteOutcome {
+    Reject,
+    Pass,
+}
+
+impl TryFrom<u32> for VoteOutcome {
+    type Error = UtfRnnError;
+
+    fn try_from(class: u32) -> Result<Self> {
+        match class {
+            0 => Ok(Self::Reject),
+            1 => Ok(Self::Pass),
+            class => Err(UtfRnnError::InvalidInput(format!(
+                "class {class} is not a binary vote outcome"
+            ))),
+        }
+    }
+}
+
+impl From<VoteOutcome> for u32 {
+    fn from(outcome: VoteOutcome) -> Self {
+        match outcome {
+            VoteOutcome::Reject => 0,
+            VoteOutcome::Pass => 1,
+        }
+    }
+}
+
+/// Predicts the class of every point of a `steps` by `steps` grid spanning
+/// `x_range` and `y_range`, both ends included, for plotting the decision
+/// boundary of a model with two vote columns. Row `i` of the result holds the
+/// points at the `i`-th y value, column `j` those at the `j`-th x value.
+pub fn decision_grid(
+    model: &MultiLevelPerceptron,
+    x_range: (f32, f32),
+    y_range: (f32, f32),
+    steps: usize,
+) -> Result<Vec<Vec<u32>>> {
+    if model.input_dim() != 2 {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "decision_grid needs a model with 2 vote columns, this one has {}",
+            model.input_dim()
+        )));
+    }
+    if steps < 2 {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "a grid needs at least 2 steps, got {steps}"
+        )));
+    }
+    let axis =
+        |(start, end): (f32, f32), i: usize| start + (end - start) * i as f32 / (steps - 1) as f32;
+    let points: Vec<f32> = (0..steps)
+        .flat_map(|i| (0..steps).flat_map(move |j| [axis(x_range, j), axis(y_range, i)]))
+        .collect();
+    let device = model.layers[0].weight().device();
+    let points = Tensor::from_vec(points, (steps * steps, 2), device)?;
+    Ok(model
+        .predict(&points)?
+        .chunks(steps)
+        .map(<[u32]>::to_vec)
+        .collect())
+}
+
+/// Parses a line of comma-separated votes such as `13, 22` into a `(1,
+/// vote_dim)` tensor.
+pub fn parse_votes(line: &str, dev: &Device) -> Result<Tensor> {
+    let votes = line
+        .trim()
+        .split(',')
+        .map(str::trim)
+        .enumerate()
+        .map(|(i, value)| {
+            value.parse::<f32>().map_err(|e| {
+                UtfRnnError::InvalidInput(format!("invalid vote {value:?} at position {i}: {e}"))
+            })
+        })
+        .collect::<Result<Vec<f32>>>()?;
+    Ok(Tensor::from_slice(&votes, (1, votes.len()), dev)?)
+}
+
+/// Writes one CSV row per row of the `(n, vote_dim)` tensor `inputs` to
+/// `path`: its votes, its class in `preds` and, when given, its row of the
+/// `(n, num_classes)` tensor `probs`. The header names the columns `x0`,
+/// `x1`, ..., `prediction`, `p0`, `p1`, ...
+pub fn write_predictions_csv(
+    path: &Path,
+    inputs: &Tensor,
+    preds: &[u32],
+    probs: Option<&Tensor>,
+) -> Result<()> {
+    let inputs = inputs.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    let probs = match probs {
+        Some(probs) => Some(probs.to_dtype(DType::F32)?.to_vec2::<f32>()?),
+        None => None,
+    };
+    if preds.len() != inputs.len() || probs.as_ref().is_some_and(|p| p.len() != inputs.len()) {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "got {} input rows, {} predictions and {} probability rows",
+            inputs.len(),
+            preds.len(),
+            probs.as_ref().map_or(0, Vec::len)
+        )));
+    }
+
+    let vote_dim = inputs.first().map_or(0, Vec::len);
+    let num_classes = probs.as_ref().and_then(|p| p.first()).map_or(0, Vec::len);
+    let mut header: Vec<String> = (0..vote_dim).map(|i| format!("x{i}")).collect();
+    header.push("prediction".to_string());
+    header.extend((0..num_classes).map(|c| format!("p{c}")));
+
+    let mut csv = header.join(",");
+    csv.push('\n');
+    for (i, (row, pred)) in inputs.iter().zip(preds).enumerate() {
+        let mut fields: Vec<String> = row.iter().map(f32::to_string).collect();
+        fields.push(pred.to_string());
+        if let Some(probs) = &probs {
+            fields.extend(probs[i].iter().map(f32::to_string));
+        }
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    fs::write(path, csv)?;
+    Ok(())
+}
+
+/// Copies the tensors of the `.safetensors` file at `path` over the variables
+/// in `varmap`, checking every expected tensor first.
+fn load_weights(varmap: &VarMap, path: &Path, dev: &Device) -> Result<()> {
+    let tensors = candle_core::safetensors::load(path, dev)?;
+    let vars = varmap.data().lock().unwrap();
+    let mut names: Vec<&String> = vars.keys().collect();
+    names.sort();
+    for name in names {
+        let var = &vars[name];
+        let Some(tensor) = tensors.get(name) else {
+            return Err(UtfRnnError::MissingTensor {
+                path: path.display().to_string(),
+                name: name.clone(),
+            });
+        };
+        if tensor.shape() != var.shape() {
+            return Err(UtfRnnError::ShapeMismatch {
+                name: format!("tensor {name} in {}", path.display()),
+                expected: var.dims().to_vec(),
+                got: tensor.dims().to_vec(),
+            });
+        }
+        var.set(&tensor.to_dtype(var.dtype())?)?;
+    }
+    Ok(())
+}
+
+/// Optimizer progress stored next to the weights of a checkpoint.
+///
+/// candle does not expose the AdamW moment estimates, so they start from zero
+/// again when an AdamW run is resumed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OptimizerState {
+    pub optimizer: Optimizer,
+    pub learning_rate: f64,
+    /// Number of optimizer steps taken so far.
+    pub steps: usize,
+}
+
+/// The JSON half of a checkpoint; the weights live in a `.safetensors` file
+/// with the same stem.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckpointMeta {
+    /// Last epoch completed before the checkpoint was written.
+    pub epoch: usize,
+    pub optimizer_state: OptimizerState,
+}
+
+/// Writes `epoch-<n>.safetensors` and `epoch-<n>.json` into `dir`.
+fn save_checkpoint(dir: &Path, varmap: &VarMap, meta: &CheckpointMeta) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let stem = dir.join(format!("epoch-{}", meta.epoch));
+    varmap.save(stem.with_extension("safetensors"))?;
+    fs::write(
+        stem.with_extension("json"),
+        serde_json::to_string_pretty(meta)?,
+    )?;
+    Ok(())
+}
+
+/// Finds the checkpoint with the highest epoch in `dir`, returning its
+/// metadata and the path of its weights.
+fn latest_checkpoint(dir: &Path) -> Result<(CheckpointMeta, PathBuf)> {
+    let mut latest: Option<(CheckpointMeta, PathBuf)> = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let meta: CheckpointMeta = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        if latest
+            .as_ref()
+            .map_or(true, |(best, _)| meta.epoch > best.epoch)
+        {
+            latest = Some((meta, path.with_extension("safetensors")));
+        }
+    }
+    latest.ok_or_else(|| {
+        UtfRnnError::InvalidInput(format!("no checkpoint found in {}", dir.display()))
+    })
+}
+
+/// Redraws every weight and bias in `varmap` from an RNG seeded with `seed`,
+/// with the weights following `init` and the biases uniform in
+/// `±1/sqrt(fan_in)`. The embedding table is not a layer and keeps candle's
+/// standard normal draw. Unlike `Device::set_seed` this
+/// also works on the CPU, whose RNG candle cannot seed.
+fn init_seeded(varmap: &VarMap, seed: u64, init: Init) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let vars = varmap.data().lock().unwrap();
+    // Visit the variables in a fixed order so the draws are reproducible.
+    let mut names: Vec<&String> = vars.keys().collect();
+    names.sort();
+    for name in names {
+        let var = &vars[name];
+        let values: Vec<f32> = match name.strip_suffix(".bias") {
+            _ if name.starts_with("embedding.") => (0..var.elem_count())
+                .map(|_| standard_normal(&mut rng))
+                .collect(),
+            Some(layer) => {
+                // Biases take their fan-in from the weight of the same layer.
+                let fan_in = vars[&format!("{layer}.weight")].dim(1)?;
+                let bound = 1. / (fan_in as f32).sqrt();
+                (0..var.elem_count())
+                    .map(|_| rng.gen_range(-bound..bound))
+                    .collect()
+            }
+            None => {
+                let (fan_out, fan_in) = var.dims2()?;
+                init.sample(fan_in, fan_out, &mut rng)
+            }
+        };
+        let values = Tensor::from_vec(values, var.dims(), var.device())?.to_dtype(var.dtype())?;
+        var.set(&values)?;
+    }
+    Ok(())
+}
+
+/// Samples from the standard normal distribution with the Box-Muller transform.
+fn standard_normal(rng: &mut StdRng) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen();
+    (-2. * u1.ln()).sqrt() * (2. * std::f32::consts::PI * u2).cos()
+}
+
+/// Takes a deep copy of every variable in `varmap`, since optimizers update
+/// the variables in place.
+fn snapshot(varmap: &VarMap) -> Result<HashMap<String, Tensor>> {
+    let vars = varmap.data().lock().unwrap();
+    let mut tensors = HashMap::with_capacity(vars.len());
+    for (name, var) in vars.iter() {
+        tensors.insert(name.clone(), var.as_tensor().copy()?);
+    }
+    Ok(tensors)
+}
+
+/// Writes the tensors of a [`snapshot`] back into `varmap`.
+fn restore(varmap: &VarMap, tensors: &HashMap<String, Tensor>) -> Result<()> {
+    for (name, var) in varmap.data().lock().unwrap().iter() {
+        var.set(&tensors[name])?;
+    }
+    Ok(())
+}
+
+/// Permutes the rows of `votes` and `results` with the same random order, so
+/// every vote row keeps its label.
+fn shuffle_rows(votes: &Tensor, results: &Tensor, rng: &mut StdRng) -> Result<(Tensor, Tensor)> {
+    let len = votes.dim(0)?;
+    let mut indices: Vec<u32> = (0..len as u32).collect();
+    indices.shuffle(rng);
+    let indices = Tensor::from_vec(indices, len, votes.device())?;
+    Ok((
+        votes.index_select(&indices, 0)?,
+        results.index_select(&indices, 0)?,
+    ))
+}
+
+/// The concrete candle optimizer built from an [`Optimizer`] choice.
+enum ActiveOptimizer {
+    Sgd(candle_nn::SGD),
+    AdamW(candle_nn::AdamW),
+}
+
+impl ActiveOptimizer {
+    fn new(optimizer: &Optimizer, vars: Vec<Var>, learning_rate: f64) -> Result<Self> {
+        match *optimizer {
+            Optimizer::Sgd => Ok(Self::Sgd(candle_nn::SGD::new(vars, learning_rate)?)),
+            Optimizer::AdamW {
+                beta1,
+                beta2,
+                weight_decay,
+            } => {
+                let params = ParamsAdamW {
+                    lr: learning_rate,
+                    beta1,
+                    beta2,
+                    weight_decay,
+                    ..Default::default()
+                };
+                Ok(Self::AdamW(candle_nn::AdamW::new(vars, params)?))
+            }
+        }
+    }
+
+    fn set_learning_rate(&mut self, learning_rate: f64) {
+        match self {
+            Self::Sgd(sgd) => sgd.set_learning_rate(learning_rate),
+            Self::AdamW(adamw) => adamw.set_learning_rate(learning_rate),
+        }
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<()> {
+        match self {
+            Self::Sgd(sgd) => sgd.step(grads)?,
+            Self::AdamW(adamw) => adamw.step(grads)?,
+        }
+        Ok(())
+    }
+}
+
+/// The variables of `varmap` outside the `frozen` layers of `config`, which
+/// must all exist.
+fn trainable_vars(varmap: &VarMap, config: &TrainConfig) -> Result<Vec<(String, Var)>> {
+    let vars = varmap.data().lock().unwrap();
+    // A variable `ln1.weight` belongs to the layer `ln1`.
+    let layer = |name: &str| name.split('.').next().unwrap_or(name).to_string();
+    if let Some(missing) = config
+        .frozen
+        .iter()
+        .find(|frozen| !vars.keys().any(|name| layer(name.as_str()) == **frozen))
+    {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "cannot freeze {missing}, the model has no such layer"
+        )));
+    }
+    Ok(vars
+        .iter()
+        .filter(|(name, _)| !config.frozen.contains(&layer(name.as_str())))
+        .map(|(name, var)| (name.clone(), var.clone()))
+        .collect())
+}
+
+/// The weight matrices of the trainable linear layers in `varmap`, which the
+/// L2 weight decay penalizes. Biases, the embedding table and frozen layers
+/// are left alone.
+fn decayed_vars(varmap: &VarMap, config: &TrainConfig) -> Result<Vec<(String, Var)>> {
+    Ok(trainable_vars(varmap, config)?
+        .into_iter()
+        .filter(|(name, _)| name.ends_with(".weight") && !name.starts_with("embedding."))
+        .collect())
+}
+
+/// Splits the trainable variables of `varmap` by the prefixes of
+/// `config.param_groups`, with the unmatched ones in a last group, and
+/// returns an optimizer for every non-empty group together with its base
+/// learning rate.
+fn group_optimizers(varmap: &VarMap, config: &TrainConfig) -> Result<Vec<(f64, ActiveOptimizer)>> {
+    let mut groups: Vec<(f64, Vec<Var>)> = config
+        .param_groups
+        .iter()
+        .map(|(_, learning_rate)| (*learning_rate, Vec::new()))
+        .collect();
+    let mut rest = Vec::new();
+    for (name, var) in trainable_vars(varmap, config)? {
+        match config
+            .param_groups
+            .iter()
+            .position(|(prefix, _)| name.starts_with(prefix.as_str()))
+        {
+            Some(group) => groups[group].1.push(var),
+            None => rest.push(var),
+        }
+    }
+    groups.push((config.learning_rate, rest));
+    groups
+        .into_iter()
+        .filter(|(_, vars)| !vars.is_empty())
+        .map(|(learning_rate, vars)| {
+            let optimizer = ActiveOptimizer::new(&config.optimizer, vars, learning_rate)?;
+            Ok((learning_rate, optimizer))
+        })
+        .collect()
+}
+
+/// Adds the gradients of `vars` in `grads` onto the ones in `total`.
+fn accumulate_grads(total: &mut GradStore, grads: &GradStore, vars: &[Var]) -> Result<()> {
+    for var in vars {
+        if let Some(grad) = grads.get(var) {
+            let sum = match total.remove(var) {
+                Some(previous) => (previous + grad)?,
+                None => grad.clone(),
+            };
+            total.insert(var, sum);
+        }
+    }
+    Ok(())
+}
+
+/// Multiplies the gradients of `vars` in place by `factor`.
+fn scale_grads(grads: &mut GradStore, vars: &[Var], factor: f64) -> Result<()> {
+    for var in vars {
+        if let Some(grad) = grads.remove(var) {
+            grads.insert(var, grad.affine(factor, 0.)?);
+        }
+    }
+    Ok(())
+}
+
+/// Global L2 norm of the gradients of `vars` in `grads`.
+fn grad_norm(grads: &GradStore, vars: &[Var]) -> Result<f32> {
+    let mut sum_sq: f32 = 0.0;
+    for var in vars {
+        if let Some(grad) = grads.get(var) {
+            sum_sq += grad
+                .sqr()?
+                .sum_all()?
+                .to_dtype(DType::F32)?
+                .to_scalar::<f32>()?;
+        }
+    }
+    Ok(sum_sq.sqrt())
+}
+
+/// Scales the gradients of `vars` in place so that their global L2 norm is at
+/// most `max_norm`, returning the norm before clipping.
+fn clip_grad_norm(grads: &mut GradStore, vars: &[Var], max_norm: f32) -> Result<f32> {
+    let norm = grad_norm(grads, vars)?;
+    if norm > max_norm {
+        scale_grads(grads, vars, (max_norm / norm) as f64)?;
+    }
+    Ok(norm)
+}
+
+/// `n_classes x n_classes` grid counting how often each true class (row) was
+/// predicted as each class (column).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfusionMatrix {
+    counts: Vec<Vec<usize>>,
+}
+
+impl ConfusionMatrix {
+    pub fn new(n_classes: usize) -> Self {
+        Self {
+            counts: vec![vec![0; n_classes]; n_classes],
+        }
+    }
+
+    pub fn n_classes(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn record(&mut self, actual: usize, predicted: usize) {
+        self.counts[actual][predicted] += 1;
+    }
+
+    /// Number of samples of class `actual` that were predicted as `predicted`.
+    pub fn count(&self, actual: usize, predicted: usize) -> usize {
+        self.counts[actual][predicted]
+    }
+
+    /// Total number of recorded samples.
+    pub fn total(&self) -> usize {
+        self.counts.iter().flatten().sum()
+    }
+
+    /// How much every count changed since `previous`, e.g. the matrix of the
+    /// epoch before, indexed like the counts.
+    pub fn diff(&self, previous: &ConfusionMatrix) -> Result<Vec<Vec<i64>>> {
+        if previous.n_classes() != self.n_classes() {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "cannot diff confusion matrices of {} and {} classes",
+                self.n_classes(),
+                previous.n_classes()
+            )));
+        }
+        Ok(self
+            .counts
+            .iter()
+            .zip(&previous.counts)
+            .map(|(row, previous_row)| {
+                row.iter()
+                    .zip(previous_row)
+                    .map(|(&count, &previous_count)| count as i64 - previous_count as i64)
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Share of all samples that were classified correctly; `0.0` when nothing
+    /// was recorded.
+    pub fn accuracy(&self) -> f32 {
+        let correct: usize = (0..self.n_classes()).map(|c| self.counts[c][c]).sum();
+        ratio(correct, self.total())
+    }
+
+    /// Share of the predictions of `class` that were correct; `0.0` when the
+    /// class was never predicted.
+    pub fn precision(&self, class: usize) -> f32 {
+        let predicted: usize = self.counts.iter().map(|row| row[class]).sum();
+        ratio(self.counts[class][class], predicted)
+    }
+
+    /// Share of the samples of `class` that were predicted as such; `0.0`
+    /// when the class never occurs.
+    pub fn recall(&self, class: usize) -> f32 {
+        let actual: usize = self.counts[class].iter().sum();
+        ratio(self.counts[class][class], actual)
+    }
+
+    /// Harmonic mean of precision and recall for `class`.
+    pub fn f1(&self, class: usize) -> f32 {
+        let (precision, recall) = (self.precision(class), self.recall(class));
+        if precision + recall == 0.0 {
+            0.0
+        } else {
+            2.0 * precision * recall / (precision + recall)
+        }
+    }
+}
+
+impl std::fmt::Display for ConfusionMatrix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for row in &self.counts {
+            let cells: Vec<String> = row.iter().map(|count| format!("{count:4}")).collect();
+            writeln!(f, "{}", cells.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+fn ratio(numerator: usize, denominator: usize) -> f32 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f32 / denominator as f32
+    }
+}
+
+/// Tallies the thresholded predictions of a multi-label `model` on `votes`
+/// against the `(n, num_labels)` `labels`, returning one 2x2 matrix per label
+/// whose class `1` is "label present", so `precision(1)` and `recall(1)` are
+/// the per-label precision and recall.
+pub fn evaluate_multilabel(
+    model: &MultiLevelPerceptron,
+    votes: &Tensor,
+    labels: &Tensor,
+) -> Result<Vec<ConfusionMatrix>> {
+    let predictions = model.predict_labels(votes)?;
+    let labels = labels.to_dtype(DType::F32)?.to_vec2::<f32>()?;
+    let mut matrices = vec![ConfusionMatrix::new(2); model.num_classes()?];
+    for (actual, predicted) in labels.iter().zip(&predictions) {
+        for (matrix, (&actual, &predicted)) in matrices.iter_mut().zip(actual.iter().zip(predicted))
+        {
+            matrix.record(usize::from(actual > 0.5), usize::from(predicted));
+        }
+    }
+    Ok(matrices)
+}
+
+/// Metrics of a trained classifier on a labelled dataset, from
+/// [`MultiLevelPerceptron::score`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalReport {
+    /// Share of correctly classified rows, as a percentage.
+    pub accuracy: f32,
+    /// Mean cross-entropy of the logits against the labels.
+    pub loss: f32,
+    pub confusion: ConfusionMatrix,
+}
+
+/// Tallies the argmax predictions of `model` on `votes` against `labels`.
+pub fn evaluate(
+    model: &MultiLevelPerceptron,
+    votes: &Tensor,
+    labels: &Tensor,
+) -> Result<ConfusionMatrix> {
+    evaluate_batched(model, votes, labels, votes.dim(0)?)
+}
+
+/// Like [`evaluate`], but runs `votes` through the model `batch_size` rows at
+/// a time so large evaluation sets fit in memory.
+pub fn evaluate_batched(
+    model: &MultiLevelPerceptron,
+    votes: &Tensor,
+    labels: &Tensor,
+    batch_size: usize,
+) -> Result<ConfusionMatrix> {
+    let mut matrix = ConfusionMatrix::new(model.num_classes()?);
+    let rows = votes.dim(0)?;
+    for start in (0..rows).step_by(batch_size.max(1)) {
+        let len = batch_size.max(1).min(rows - start);
+        let predictions = model.predict(&votes.narrow(0, start, len)?)?;
+        let labels = labels.narrow(0, start, len)?.to_vec1::<u32>()?;
+        for (&actual, &predicted) in labels.iter().zip(&predictions) {
+            if actual as usize >= matrix.n_classes() {
+                return Err(UtfRnnError::InvalidInput(format!(
+                    "label {actual} is out of range for {} classes",
+                    matrix.n_classes()
+                )));
+            }
+            matrix.record(actual as usize, predicted as usize);
+        }
+    }
+    Ok(matrix)
+}
+
+/// Scores of a model on one evaluation set during training, from
+/// [`score_in_chunks`].
+struct ChunkScores {
+    /// Mean task loss over the whole set.
+    loss: f32,
+    /// Share of right decisions as a percentage, scored the way `task`
+    /// scores the test set; `0` for regression.
+    accuracy: f32,
+    /// Argmax prediction of every row for classification, empty otherwise.
+    predictions: Vec<u32>,
+}
+
+/// Scores `model` on `votes` against `targets` in evaluation mode,
+/// `batch_size` rows at a time when given. Only one chunk of logits is alive
+/// at a time: the loss and correct decisions of every chunk are added up
+/// instead of concatenating the logits.
+fn score_in_chunks(
+    model: &MultiLevelPerceptron,
+    config: &TrainConfig,
+    votes: &Tensor,
+    targets: &Tensor,
+    class_weights: Option<&Tensor>,
+    batch_size: Option<usize>,
+) -> Result<ChunkScores> {
+    let rows = votes.dim(0)?;
+    // An empty set has no mean loss to report.
+    if rows == 0 {
+        return Err(UtfRnnError::InvalidInput(
+            "cannot score an empty evaluation set".to_string(),
+        ));
+    }
+    let batch_size = batch_size.filter(|&b| b > 0).unwrap_or(rows).max(1);
+    let mut loss_sum: f32 = 0.0;
+    let mut loss_weight: f32 = 0.0;
+    let mut correct: f32 = 0.0;
+    let mut decisions = 0;
+    let mut predictions = Vec::new();
+    for start in (0..rows).step_by(batch_size) {
+        let len = batch_size.min(rows - start);
+        let logits = model.logits(&votes.narrow(0, start, len)?)?;
+        let targets = targets.narrow(0, start, len)?;
+        // Weigh the mean loss of every chunk by what it averages over, so the
+        // chunks add up to the mean over the whole set.
+        let weight = match (config.task, class_weights) {
+            (Task::Classification, Some(weights)) => weights
+                .index_select(&targets, 0)?
+                .sum_all()?
+                .to_scalar::<f32>()?,
+            _ => len as f32,
+        };
+        if weight > 0.0 {
+            let loss = task_loss(config, &logits, &targets, class_weights)?;
+            loss_sum += loss.to_scalar::<f32>()? * weight;
+            loss_weight += weight;
+        }
+        let right = match config.task {
+            Task::Classification => {
+                let chunk = logits.argmax(D::Minus1)?;
+                predictions.extend(chunk.to_vec1::<u32>()?);
+                chunk.eq(&targets)?
+            }
+            Task::Regression => continue,
+            Task::MultiLabel { .. } => logits
+                .gt(0f32)?
+                .to_dtype(DType::F32)?
+                .eq(&targets.to_dtype(DType::F32)?)?,
+        };
+        correct += right.to_dtype(DType::F32)?.sum_all()?.to_scalar::<f32>()?;
+        decisions += targets.elem_count();
+    }
+    Ok(ChunkScores {
+        loss: loss_sum / loss_weight,
+        accuracy: if decisions == 0 {
+            0.0
+        } else {
+            100. * correct / decisions as f32
+        },
+        predictions,
+    })
+}
+
+/// Loss and accuracy recorded at the end of a training epoch.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EpochMetrics {
+    pub epoch: usize,
+    pub train_loss: f32,
+    /// Exponential moving average of `train_loss` with weight
+    /// `TrainConfig::ema_alpha`, starting from the first epoch's loss.
+    pub train_loss_ema: f32,
+    pub test_loss: f32,
+    /// Loss on the validation set, when the dataset has one.
+    pub val_loss: Option<f32>,
+    /// Test accuracy as a percentage; `0` for regression, and the share of
+    /// correct label decisions for multi-label tasks.
+    pub test_accuracy: f32,
+    /// Accuracy on the training set, scored like `test_accuracy` with dropout
+    /// off; only with [`TrainConfig::compute_train_accuracy`].
+    pub train_accuracy: Option<f32>,
+    /// Test accuracy of every class (its recall, from `0.0` to `1.0`), in
+    /// class order; empty for regression and multi-label tasks.
+    pub per_class_accuracy: Vec<f32>,
+    /// Test confusion matrix, with [`TrainConfig::track_confusion`] and for
+    /// classification only.
+    pub confusion: Option<ConfusionMatrix>,
+    /// Root mean squared error on the test set, for regression only.
+    pub test_rmse: Option<f32>,
+    /// Learning rate used during the epoch.
+    pub learning_rate: f64,
+    /// Global L2 norm of the gradients, before clipping, averaged over the
+    /// optimizer steps of the epoch.
+    pub grad_norm: f32,
+}
+
+impl EpochMetrics {
+    /// Writes all fields as a single-line JSON object followed by a newline.
+    pub fn write_json_line(&self, writer: &mut dyn Write) -> Result<()> {
+        serde_json::to_writer(&mut *writer, self)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+}
+
+/// Hook invoked at the end of every epoch; returning `ControlFlow::Break`
+/// stops training early.
+pub type EpochCallback<'a> = &'a mut dyn FnMut(&EpochMetrics) -> ControlFlow<()>;
+
+/// A trained model together with the variable map holding its weights.
+pub struct TrainedModel {
+    pub model: MultiLevelPerceptron,
+    pub varmap: VarMap,
+    /// Metrics for every epoch that was run, in order.
+    pub metrics: Vec<EpochMetrics>,
+}
+
+/// Outcome of a single training run, whether or not it converged.
+pub struct FitOutcome {
+    pub trained: TrainedModel,
+    /// Number of epochs that were actually run.
+    pub epochs: usize,
+    /// Test accuracy after the last epoch, as a percentage.
+    pub accuracy: f32,
+}
+
+/// Receives progress from [`train`] and [`train_until_converged`], e.g. to
+/// drive a UI. Every method does nothing by default.
+pub trait TrainObserver {
+    /// Called at the end of every epoch of every attempt.
+    fn on_epoch(&mut self, _m: &EpochMetrics) {}
+    /// Called before the 1-based `attempt` starts training.
+    fn on_attempt_start(&mut self, _attempt: usize) {}
+    /// Called once when training is over, with whether it succeeded.
+    fn on_finish(&mut self, _result: &Result<()>) {}
+}
+
+/// Observer that ignores all progress.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SilentObserver;
+
+impl TrainObserver for SilentObserver {}
+
+/// Observer printing progress to stdout in the format of the original demo.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdoutObserver;
+
+impl TrainObserver for StdoutObserver {
+    fn on_epoch(&mut self, m: &EpochMetrics) {
+        println!(
+            "Epoch: {:3} Train loss: {:8.5} (EMA: {:8.5}) Test accuracy: {:5.2}%",
+            m.epoch, m.train_loss, m.train_loss_ema, m.test_accuracy
+        );
+    }
+
+    fn on_attempt_start(&mut self, attempt: usize) {
+        println!("Trying to train neural network (attempt {attempt}).");
+    }
+
+    fn on_finish(&mut self, result: &Result<()>) {
+        if let Err(e) = result {
+            println!("Error: {e}");
+        }
+    }
+}
+
+/// Observer writing the metrics of every epoch to `writer` as JSON lines,
+/// like [`MetricsFormat::JsonLines`] does to stdout.
+pub struct JsonLinesObserver<W: Write> {
+    writer: W,
+    /// First write error, reported by [`JsonLinesObserver::finish`].
+    error: Option<UtfRnnError>,
+}
+
+impl<W: Write> JsonLinesObserver<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Returns the writer, or the first error writing to it.
+    pub fn finish(self) -> Result<W> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.writer),
+        }
+    }
+}
+
+impl<W: Write> TrainObserver for JsonLinesObserver<W> {
+    fn on_epoch(&mut self, m: &EpochMetrics) {
+        if self.error.is_none() {
+            self.error = m.write_json_line(&mut self.writer).err();
+        }
+    }
+}
+
+/// Hands the outcome of a run to `observer` before returning it.
+fn report_finish<T>(observer: &mut dyn TrainObserver, result: Result<T>) -> Result<T> {
+    match result {
+        Ok(value) => {
+            observer.on_finish(&Ok(()));
+            Ok(value)
+        }
+        Err(e) => {
+            let failed = Err(e);
+            observer.on_finish(&failed);
+            Err(failed.unwrap_err())
+        }
+    }
+}
+
+/// Trains a single model, failing with [`UtfRnnError::NotTrained`] unless it
+/// reaches `config.target_accuracy`.
+pub fn train(
+    m: Dataset,
+    config: &TrainConfig,
+    dev: &Device,
+    observer: &mut dyn TrainObserver,
+) -> Result<TrainedModel> {
+    observer.on_attempt_start(1);
+    let result = train_attempt(m, config, dev, observer);
+    report_finish(observer, result)
+}
+
+/// One run of [`train`], without the final report to `observer`.
+fn train_attempt(
+    m: Dataset,
+    config: &TrainConfig,
+    dev: &Device,
+    observer: &mut dyn TrainObserver,
+) -> Result<TrainedModel> {
+    let mut on_epoch = |metrics: &EpochMetrics| {
+        observer.on_epoch(metrics);
+        ControlFlow::Continue(())
+    };
+    let outcome = fit(m, config, dev, Some(&mut on_epoch))?;
+
+    // If the final accuracy misses the target, return an error indicating the model is not trained well enough.
+    if outcome.accuracy < config.target_accuracy {
+        Err(UtfRnnError::NotTrained {
+            epochs: outcome.epochs,
+            accuracy: outcome.accuracy,
+            target: config.target_accuracy,
+        })
+    } else {
+        // Otherwise, return the trained model.
+        Ok(outcome.trained)
+    }
+}
+
+/// The small two-candidate voting dataset of the demo: eight training and
+/// three test rows of `VOTE_DIM` vote counts, labelled `1` when the first
+/// candidate should win.
+pub fn sample_dataset(dev: &Device) -> Result<Dataset> {
+    Dataset::from_vote_counts(
+        &[15, 10, 10, 15, 5, 12, 30, 20, 16, 12, 13, 25, 6, 14, 31, 21],
+        &[1, 0, 0, 1, 1, 0, 0, 1],
+        &[13, 9, 8, 14, 3, 10],
+        &[1, 0, 0],
+        VOTE_DIM,
+        dev,
+    )
+}
+
+/// [`sample_dataset`] with a test set of one vote row labelled both ways,
+/// which no model classifies perfectly, so the accuracy never stops a run
+/// early.
+#[cfg(test)]
+fn unlearnable_dataset(dev: &Device) -> Result<Dataset> {
+    let mut m = sample_dataset(dev)?;
+    m.test_votes = Tensor::new(&[[13f32, 9.], [13., 9.]], dev)?;
+    m.test_results = Tensor::new(&[0u32, 1], dev)?;
+    Ok(m)
+}
+
+/// Trains on [`sample_dataset`] until a run converges, as the demo does, and
+/// returns the dataset with the trained model.
+pub fn train_demo(config: &TrainConfig, dev: &Device) -> Result<(Dataset, MultiLevelPerceptron)> {
+    let m = sample_dataset(dev)?;
+    let trained = train_until_converged(m.clone(), config, MAX_ATTEMPTS, dev, &mut SilentObserver)?;
+    Ok((m, trained.model))
+}
+
+/// Retries training with fresh weights, and the learning rates scaled by
+/// `config.lr_backoff` after every failure, until a run reaches
+/// `config.target_accuracy`, giving up after `max_attempts` runs. With
+/// `config.accept_best` the most accurate attempt is returned instead of an
+/// error when none converges. If every attempt diverged, the error of the
+/// last one is returned.
+pub fn train_until_converged(
+    m: Dataset,
+    config: &TrainConfig,
+    max_attempts: usize,
+    dev: &Device,
+    observer: &mut dyn TrainObserver,
+) -> Result<TrainedModel> {
+    let result = converge(m, config, max_attempts, dev, observer);
+    report_finish(observer, result)
+}
+
+/// The attempts of [`train_until_converged`], without the final report to
+/// `observer`.
+fn converge(
+    m: Dataset,
+    config: &TrainConfig,
+    max_attempts: usize,
+    dev: &Device,
+    observer: &mut dyn TrainObserver,
+) -> Result<TrainedModel> {
+    check_lr_backoff(config)?;
+    // Build the model once; every attempt redraws its weights in place.
+    let mut varmap = VarMap::new();
+    let mut model = PerceptronBuilder::from_config(config)
+        .input_dim(m.input_dim()?)
+        .build(VarBuilder::from_varmap(&varmap, config.dtype, dev))?;
+    // Most accurate attempt so far, with its test accuracy, and with a copy
+    // of its weights when `config.accept_best` may return it, since later
+    // attempts overwrite the shared variables.
+    let mut best: Option<(f32, Option<(HashMap<String, Tensor>, TrainedModel)>)> = None;
+    // Divergence of the last attempt that stopped with a non-finite loss.
+    let mut diverged = None;
+
+    for attempt in 1..max_attempts + 1 {
+        let _span = info_span!("attempt", attempt, max_attempts).entered();
+        // Offset a fixed seed per attempt so retries stay reproducible but differ.
+        // A separate `data_seed` keeps the row order of every attempt.
+        let offset = |seed: u64| seed.wrapping_add(attempt as u64 - 1);
+        // Lower the learning rates once for every failed attempt.
+        let backoff = (config.lr_backoff as f64).powi(attempt as i32 - 1);
+        let config = TrainConfig {
+            seed: config.seed.map(offset),
+            init_seed: config.init_seed.map(offset),
+            learning_rate: config.learning_rate * backoff,
+            param_groups: config
+                .param_groups
+                .iter()
+                .map(|(prefix, lr)| (prefix.clone(), lr * backoff))
+                .collect(),
+            ..config.clone()
+        };
+        if config.verbose {
+            info!(
+                learning_rate = config.learning_rate,
+                "Trying to train neural network (attempt {attempt}/{max_attempts})."
+            );
+        }
+        let seed = config.weights_seed().unwrap_or_else(rand::random);
+        model.reinit(&mut varmap, seed)?;
+        observer.on_attempt_start(attempt);
+        let mut on_epoch = |metrics: &EpochMetrics| {
+            observer.on_epoch(metrics);
+            ControlFlow::Continue(())
+        };
+        let fitted = fit_from(
+            m.clone(),
+            &config,
+            dev,
+            Some(&mut on_epoch),
+            None,
+            Some(varmap.clone()),
+        );
+        let outcome = match fitted {
+            // A diverging attempt fails like any other and is retried, at a
+            // lower learning rate with a backoff.
+            Err(UtfRnnError::Train(e @ TrainError::NonFiniteLoss { .. })) => {
+                warn!(attempt, learning_rate = config.learning_rate, "{e}");
+                diverged = Some(e);
+                continue;
+            }
+            outcome => outcome?,
+        };
+        if outcome.accuracy >= config.target_accuracy {
+            return Ok(outcome.trained);
+        }
+        let e = UtfRnnError::NotTrained {
+            epochs: outcome.epochs,
+            accuracy: outcome.accuracy,
+            target: config.target_accuracy,
+        };
+        warn!(attempt, accuracy = outcome.accuracy, "{e}");
+        if best
+            .as_ref()
+            .map_or(true, |(accuracy, _)| outcome.accuracy > *accuracy)
+        {
+            let kept = if config.accept_best {
+                Some((snapshot(&varmap)?, outcome.trained))
+            } else {
+                None
+            };
+            best = Some((outcome.accuracy, kept));
+        }
+    }
+
+    match (best, diverged) {
+        (Some((_, Some((weights, trained)))), _) => {
+            restore(&trained.varmap, &weights)?;
+            Ok(trained)
+        }
+        // No attempt finished, so report why the last one failed.
+        (None, Some(e)) => Err(e.into()),
+        (best, _) => Err(UtfRnnError::NotConverged {
+            attempts: max_attempts,
+            best_accuracy: best.map_or(0.0, |(accuracy, _)| accuracy),
+            target: config.target_accuracy,
+        }),
+    }
+}
+
+/// Trains a model with every hidden-layer layout of `candidates` on
+/// `dataset`, otherwise configured by `config`, and returns each layout with
+/// the best test accuracy of any of its epochs, as a percentage, most
+/// accurate first.
+///
+/// Every candidate starts from the same seed, `config.seed` or `0`, so none
+/// is favoured by a luckier initialization.
+pub fn search_architectures(
+    dataset: &Dataset,
+    candidates: Vec<Vec<usize>>,
+    config: &TrainConfig,
+    dev: &Device,
+) -> Result<Vec<(Vec<usize>, f32)>> {
+    let mut scores = Vec::with_capacity(candidates.len());
+    for hidden_sizes in candidates {
+        let _span = info_span!("search_architectures", ?hidden_sizes).entered();
+        let config = TrainConfig {
+            hidden_sizes: hidden_sizes.clone(),
+            seed: Some(config.seed.unwrap_or(0)),
+            ..config.clone()
+        };
+        let outcome = fit(dataset.clone(), &config, dev, None)?;
+        let best = outcome
+            .trained
+            .metrics
+            .iter()
+            .map(|metrics| metrics.test_accuracy)
+            .fold(outcome.accuracy, f32::max);
+        if config.verbose {
+            info!(accuracy = best, "candidate finished");
+        }
+        scores.push((hidden_sizes, best));
+    }
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(scores)
+}
+
+/// Estimates the accuracy of `config` with k-fold cross-validation: splits the
+/// rows of `votes` and `labels` into `k` contiguous folds, trains a fresh
+/// model on every `k - 1` of them and returns the accuracy on the held-out
+/// fold, as a percentage, in fold order.
+///
+/// The held-out fold is the test set of its run, so early stopping on
+/// accuracy sees it; shuffle the rows first if they are ordered.
+pub fn cross_validate(
+    votes: &Tensor,
+    labels: &Tensor,
+    k: usize,
+    config: &TrainConfig,
+    dev: &Device,
+) -> Result<Vec<f32>> {
+    let rows = votes.dim(0)?;
+    if k < 2 || k > rows {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "k must be between 2 and the {rows} rows, got {k}"
+        )));
+    }
+    let _span = info_span!("cross_validate", k).entered();
+    let bounds: Vec<usize> = (0..k + 1).map(|fold| fold * rows / k).collect();
+    let mut accuracies = Vec::with_capacity(k);
+    for fold in 0..k {
+        let (start, end) = (bounds[fold], bounds[fold + 1]);
+        // The rows before and after the held-out fold.
+        let rest = |xs: &Tensor| -> Result<Tensor> {
+            let before = xs.narrow(0, 0, start)?;
+            let after = xs.narrow(0, end, rows - end)?;
+            Ok(Tensor::cat(&[&before, &after], 0)?)
+        };
+        let m = Dataset {
+            train_votes: rest(votes)?,
+            train_results: rest(labels)?,
+            test_votes: votes.narrow(0, start, end - start)?,
+            test_results: labels.narrow(0, start, end - start)?,
+            validation: None,
+        };
+        let outcome = fit(m, config, dev, None)?;
+        if config.verbose {
+            info!(fold, accuracy = outcome.accuracy, "fold finished");
+        }
+        accuracies.push(outcome.accuracy);
+    }
+    Ok(accuracies)
+}
+
+/// Trains a fresh model for up to `config.epochs` epochs, stopping early once
+/// the test accuracy reaches `config.target_accuracy` with
+/// `config.stop_on_accuracy` set, or `on_epoch` breaks.
+pub fn fit(
+    m: Dataset,
+    config: &TrainConfig,
+    dev: &Device,
+    on_epoch: Option<EpochCallback>,
+) -> Result<FitOutcome> {
+    fit_from(m, config, dev, on_epoch, None, None)
+}
+
+/// Like [`fit`], but builds the model on `varmap`, so weights already in it
+/// (e.g. loaded from a pretrained model) are fine-tuned instead of being
+/// initialized afresh. The optimizer updates exactly the variables in the map.
+pub fn train_with_varmap(
+    varmap: VarMap,
+    m: Dataset,
+    config: &TrainConfig,
+    dev: &Device,
+) -> Result<FitOutcome> {
+    fit_from(m, config, dev, None, None, Some(varmap))
+}
+
+/// Loads the latest checkpoint in `checkpoint_dir` and keeps training from the
+/// epoch after it up to `config.epochs`.
+///
+/// The config must describe the same model and data order as the
+/// interrupted run. Early-stopping progress is not checkpointed and starts
+/// over.
+pub fn resume_training(
+    checkpoint_dir: &Path,
+    m: Dataset,
+    config: &TrainConfig,
+    dev: &Device,
+) -> Result<TrainedModel> {
+    let checkpoint = latest_checkpoint(checkpoint_dir)?;
+    Ok(fit_from(m, config, dev, None, Some(&checkpoint), None)?.trained)
+}
+
+/// Loss of the model `outputs` against `targets` for the configured task.
+fn task_loss(
+    config: &TrainConfig,
+    outputs: &Tensor,
+    targets: &Tensor,
+    class_weights: Option<&Tensor>,
+) -> Result<Tensor> {
+    match config.task {
+        Task::Classification => {
+            config
+                .loss
+                .compute_smoothed(outputs, targets, class_weights, config.label_smoothing)
+        }
+        Task::Regression => Ok(loss::mse(
+            &outputs.squeeze(1)?,
+            &targets.to_dtype(DType::F32)?,
+        )?),
+        Task::MultiLabel { .. } => Ok(loss::binary_cross_entropy_with_logit(
+            outputs,
+            &targets.to_dtype(DType::F32)?,
+        )?),
+    }
+}
+
+/// Checks that `labels` holds exactly one target per row of `votes`: a class
+/// or value, or a row of `num_labels` flags for multi-label tasks.
+fn check_labels(name: &str, votes: &Tensor, labels: &Tensor, task: Task) -> Result<()> {
+    let rows = votes.dims().first().copied().unwrap_or(0);
+    let expected = match task {
+        Task::Classification | Task::Regression => vec![rows],
+        Task::MultiLabel { num_labels } => vec![rows, num_labels],
+    };
+    if labels.dims() != expected {
+        return Err(UtfRnnError::ShapeMismatch {
+            name: name.to_string(),
+            expected,
+            got: labels.dims().to_vec(),
+        });
+    }
+    Ok(())
+}
+
+/// Moves the moving average `average` towards `value` by the share `alpha`.
+fn ema(average: f32, value: f32, alpha: f32) -> f32 {
+    alpha * value + (1.0 - alpha) * average
+}
+
+fn check_ema_alpha(config: &TrainConfig) -> Result<()> {
+    if !(config.ema_alpha > 0.0 && config.ema_alpha <= 1.0) {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "ema_alpha must be in (0, 1], got {}",
+            config.ema_alpha
+        )));
+    }
+    Ok(())
+}
+
+fn check_lr_backoff(config: &TrainConfig) -> Result<()> {
+    if !(config.lr_backoff > 0.0 && config.lr_backoff <= 1.0) {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "lr_backoff must be in (0, 1], got {}",
+            config.lr_backoff
+        )));
+    }
+    Ok(())
+}
+
+fn check_label_smoothing(config: &TrainConfig) -> Result<()> {
+    if !(0.0..1.0).contains(&config.label_smoothing) {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "label_smoothing must be in [0, 1), got {}",
+            config.label_smoothing
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the class weights, if any, belong to a classification task
+/// and have one entry per class.
+fn check_class_weights(config: &TrainConfig) -> Result<()> {
+    match &config.class_weights {
+        Some(_) if config.task != Task::Classification => Err(UtfRnnError::InvalidInput(
+            "class weights only apply to classification".to_string(),
+        )),
+        Some(weights) if weights.len() != config.num_classes => {
+            Err(UtfRnnError::InvalidInput(format!(
+                "got {} class weights for {} classes",
+                weights.len(),
+                config.num_classes
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that the target accuracy is a percentage a run can reach.
+fn check_target_accuracy(config: &TrainConfig) -> Result<()> {
+    if !(config.target_accuracy > 0.0 && config.target_accuracy <= 100.0) {
+        return Err(UtfRnnError::InvalidInput(format!(
+            "target_accuracy must be in (0, 100], got {}",
+            config.target_accuracy
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that balanced sampling, if enabled, has classes to balance.
+fn check_balanced_sampling(config: &TrainConfig) -> Result<()> {
+    if config.balanced_sampling && config.task != Task::Classification {
+        return Err(UtfRnnError::InvalidInput(
+            "balanced sampling only applies to classification".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `dataset` and `config` can be trained together without
+/// training anything: the votes and results have matching shapes, class
+/// labels have the `U32` dtype and lie in `0..num_classes`, the layer sizes
+/// and options of `config` are valid, and the device holding the dataset
+/// works with `config.dtype`.
+pub fn validate_setup(dataset: &Dataset, config: &TrainConfig) -> Result<()> {
+    let input_dim = dataset.input_dim()?;
+    if dataset.train_votes.dim(0)? == 0 {
+        return Err(UtfRnnError::InvalidInput(
+            "the training set is empty".to_string(),
+        ));
+    }
+    if dataset.test_votes.dim(0)? == 0 {
+        return Err(UtfRnnError::InvalidInput(
+            "the test set is empty".to_string(),
+        ));
+    }
+    let mut splits = vec![
+        (
+            "train results",
+            &dataset.train_votes,
+            &dataset.train_results,
+        ),
+        ("test results", &dataset.test_votes, &dataset.test_results),
+    ];
+    if let Some((votes, results)) = &dataset.validation {
+        splits.push(("validation results", votes, results));
+    }
+    for (name, votes, results) in splits {
+        check_labels(name, votes, results, config.task)?;
+        if config.task != Task::Classification {
+            continue;
+        }
+        if results.dtype() != DType::U32 {
+            return Err(UtfRnnError::DTypeMismatch {
+                name: name.to_string(),
+                expected: DType::U32,
+                got: results.dtype(),
+            });
+        }
+        if let Some(&label) = results
+            .to_vec1::<u32>()?
+            .iter()
+            .find(|&&label| label as usize >= config.num_classes)
+        {
+            return Err(UtfRnnError::LabelOutOfRange {
+                name: name.to_string(),
+                label: label as usize,
+                num_classes: config.num_classes,
+            });
+        }
+    }
+
+    // Build the model once on the CPU to check the layer sizes and dropout.
+    let varmap = VarMap::new();
+    PerceptronBuilder::from_config(config)
+        .input_dim(input_dim)
+        .build(VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu))?;
+    check_class_weights(config)?;
+    check_balanced_sampling(config)?;
+    check_target_accuracy(config)?;
+    check_lr_backoff(config)?;
+    check_ema_alpha(config)?;
+    check_label_smoothing(config)?;
+    if config.normalize && config.input_mode != InputMode::Float {
+        return Err(UtfRnnError::InvalidInput(
+            "normalize only applies to float inputs".to_string(),
+        ));
+    }
+
+    // Round-trip a value through the device in the training dtype.
+    let dev = dataset.train_votes.device();
+    Tensor::zeros(1, config.dtype, dev)
+        .and_then(|t| t.to_dtype(DType::F32)?.to_vec1::<f32>())
+        .map_err(|e| {
+            UtfRnnError::Device(format!(
+                "{dev:?} cannot hold {:?} tensors: {e}",
+                config.dtype
+            ))
+        })?;
+    Ok(())
+}
+
+/// Like `fit`, optionally starting from a checkpoint's weights and epoch, or
+/// from the weights already in `pretrained`.
+fn fit_from(
+    m: Dataset,
+    config: &TrainConfig,
+    dev: &Device,
+    mut on_epoch: Option<EpochCallback>,
+    resume: Option<&(CheckpointMeta, PathBuf)>,
+    pretrained: Option<VarMap>,
+) -> Result<FitOutcome> {
+    let _span = info_span!("train", epochs = config.epochs).entered();
+    check_labels(
+        "train results",
+        &m.train_votes,
+        &m.train_results,
+        config.task,
+    )?;
+    check_labels("test results", &m.test_votes, &m.test_results, config.task)?;
+    if let Some((votes, results)) = &m.validation {
+        check_labels("validation results", votes, results, config.task)?;
+    }
+    // Move the training results tensor to the specified device (e.g., GPU or CPU).
+    let train_results = m.train_results.to_device(dev)?;
+    // Float votes are cast to the training dtype, category ids to `U32`.
+    let votes_dtype = match config.input_mode {
+        InputMode::Float => config.dtype,
+        InputMode::Categorical { .. } => DType::U32,
+    };
+    // Move the training votes tensor to the specified device.
+    let train_votes = m.train_votes.to_device(dev)?.to_dtype(votes_dtype)?;
+
+    // Seed the device RNG; the CPU one can't be seeded and is covered by `init_seeded` below.
+    if let Some(seed) = config.weights_seed() {
+        if !dev.is_cpu() {
+            dev.set_seed(seed)?;
+        }
+    }
+
+    // Create a variable map to store model parameters, unless one was given.
+    let fine_tuning = pretrained.is_some();
+    let varmap = pretrained.unwrap_or_else(VarMap::new);
+    // Create a variable builder from the variable map, specifying the data type and device.
+    let vs = VarBuilder::from_varmap(&varmap, config.dtype, dev);
+    // Initialize the multi-level perceptron model with the variable builder,
+    // as wide as the votes of the dataset.
+    let mut model = PerceptronBuilder::from_config(config)
+        .input_dim(m.input_dim()?)
+        .build(vs.clone())?;
+    // Standardize the inputs with statistics taken from the training votes only.
+    if config.normalize {
+        if config.input_mode != InputMode::Float {
+            return Err(UtfRnnError::InvalidInput(
+                "normalize only applies to float inputs".to_string(),
+            ));
+        }
+        model.standardizer = Some(Standardizer::fit(&train_votes)?);
+    }
+    // With a seed, redraw the initial weights deterministically on any device.
+    // Pretrained weights are kept as they are.
+    if let Some(seed) = config.weights_seed().filter(|_| !fine_tuning) {
+        init_seeded(&varmap, seed, config.init)?;
+    }
+    // When resuming, continue from the checkpointed weights instead.
+    if let Some((_, weights)) = resume {
+        load_weights(&varmap, weights, dev)?;
+    }
+
+    // Initialize the configured optimizer for every parameter group with its learning rate,
+    // leaving out the frozen layers.
+    let vars: Vec<Var> = trainable_vars(&varmap, config)?
+        .into_iter()
+        .map(|(_, var)| var)
+        .collect();
+    let mut optimizers = group_optimizers(&varmap, config)?;
+    // Weight matrices penalized by the L2 weight decay.
+    let decayed: Vec<Var> = decayed_vars(&varmap, config)?
+        .into_iter()
+        .map(|(_, var)| var)
+        .collect();
+
+    check_ema_alpha(config)?;
+    check_label_smoothing(config)?;
+    check_target_accuracy(config)?;
+    // Per-class loss weights, one for every class the model scores.
+    check_class_weights(config)?;
+    let class_weights = match &config.class_weights {
+        Some(weights) => Some(Tensor::from_slice(weights, weights.len(), dev)?),
+        None => None,
+    };
+    // Oversample the rare classes when balancing the batches.
+    check_balanced_sampling(config)?;
+    let sampler = if config.balanced_sampling {
+        Some(WeightedSampler::new(&train_results, model.num_classes()?)?)
+    } else {
+        None
+    };
+
+    // Move the test votes tensor to the specified device.
+    let test_votes = m.test_votes.to_device(dev)?.to_dtype(votes_dtype)?;
+    // Move the test results tensor to the specified device.
+    let test_results = m.test_results.to_device(dev)?;
+    // Move the validation set, if any, to the specified device.
+    let validation = match &m.validation {
+        Some((votes, results)) => Some((
+            votes.to_device(dev)?.to_dtype(votes_dtype)?,
+            results.to_device(dev)?,
+        )),
+        None => None,
+    };
+
+    // Variable to store the final accuracy of the model.
+    let mut final_accuracy: f32 = 0.0;
+    // Metrics collected at the end of every epoch.
+    let mut metrics: Vec<EpochMetrics> = Vec::with_capacity(config.epochs);
+    // Lowest monitored loss so far, with the weights and test accuracy it was reached with.
+    let mut best: Option<(f32, HashMap<String, Tensor>, f32)> = None;
+    // Number of epochs since the monitored loss last improved.
+    let mut epochs_without_improvement = 0;
+    // Whether the early-stopping patience ran out.
+    let mut plateaued = false;
+
+    // Random number generator used to shuffle the training rows.
+    let mut rng = config.data_rng();
+    // Number of training rows and how many of them go into each optimizer step.
+    let train_len = train_votes.dim(0)?;
+    let batch_size = config.batch_size.unwrap_or(train_len).max(1);
+    let shuffling = config.shuffle || config.batch_size.is_some();
+    let grad_accum_steps = config.grad_accum_steps.max(1);
+    // Number of optimizer steps taken, recorded in checkpoints.
+    let mut steps = resume.map_or(0, |(meta, _)| meta.optimizer_state.steps);
+
+    // A resumed run starts after the checkpointed epoch, replaying the
+    // shuffles of the skipped epochs so the rows come in the same order.
+    let first_epoch = resume.map_or(1, |(meta, _)| meta.epoch + 1);
+    for _ in 1..first_epoch {
+        if let Some(sampler) = &sampler {
+            sampler.epoch_indices(&mut rng);
+        } else if shuffling {
+            (0..train_len as u32).collect::<Vec<_>>().shuffle(&mut rng);
+        }
+    }
+
+    // Training loop for the specified number of epochs.
+    for epoch in first_epoch..config.epochs + 1 {
+        // Apply the learning rate scheduled for this epoch.
+        let learning_rate = config.learning_rate_at(epoch);
+        for (base_lr, optimizer) in &mut optimizers {
+            optimizer.set_learning_rate(config.group_learning_rate_at(*base_lr, epoch));
+        }
+
+        // Visit the rows in a fresh random order every epoch when shuffling or mini-batching.
+        // Balanced sampling draws its own rows instead.
+        let (epoch_votes, epoch_results) = if let Some(sampler) = &sampler {
+            let indices = sampler.epoch_indices(&mut rng);
+            let indices = Tensor::from_vec(indices, train_len, dev)?;
+            (
+                train_votes.index_select(&indices, 0)?,
+                train_results.index_select(&indices, 0)?,
+            )
+        } else if shuffling {
+            shuffle_rows(&train_votes, &train_results, &mut rng)?
+        } else {
+            (train_votes.clone(), train_results.clone())
+        };
+
+        // Sum of the per-row training loss, used to report the epoch average.
+        let mut loss_sum: f32 = 0.0;
+        // Gradients summed over the micro-batches since the last optimizer step.
+        let mut accumulated: Option<GradStore> = None;
+        let mut micro_batches = 0;
+        // Sum of the gradient norms of the optimizer steps, before clipping.
+        let mut grad_norm_sum: f32 = 0.0;
+        let mut epoch_steps = 0;
+        for start in (0..train_len).step_by(batch_size) {
+            // Slice out the rows of this batch.
+            let len = batch_size.min(train_len - start);
+            let batch_votes = epoch_votes.narrow(0, start, len)?;
+            let batch_results = epoch_results.narrow(0, start, len)?;
+
+            // Forward pass: compute the logits (raw predictions) for the training data.
+            let logits = model.forward_t(&batch_votes, true)?;
+            // Compute the loss between the logits and the true labels.
+            let loss = task_loss(config, &logits, &batch_results, class_weights.as_ref())?;
+
+            // Add the L2 penalty on the weights to the loss being minimized.
+            let objective = if config.weight_decay > 0.0 {
+                let mut penalty = Tensor::zeros((), DType::F32, dev)?;
+                for var in &decayed {
+                    penalty = (penalty + var.sqr()?.sum_all()?.to_dtype(DType::F32)?)?;
+                }
+                (&loss + (penalty * config.weight_decay as f64)?)?
+            } else {
+                loss.clone()
+            };
+
+            // Backpropagate and add the gradients to those of the earlier micro-batches.
+            let grads = objective.backward()?;
+            accumulated = Some(match accumulated.take() {
+                Some(mut total) => {
+                    accumulate_grads(&mut total, &grads, &vars)?;
+                    total
+                }
+                None => grads,
+            });
+            micro_batches += 1;
+            loss_sum += loss.to_scalar::<f32>()? * len as f32;
+
+            // Every `grad_accum_steps` micro-batches, and after the last one,
+            // average the gradients, clip them if configured, and update the model parameters.
+            if micro_batches == grad_accum_steps || start + len == train_len {
+                let mut grads = accumulated.take().expect("at least one micro-batch");
+                if micro_batches > 1 {
+                    scale_grads(&mut grads, &vars, 1. / micro_batches as f64)?;
+                }
+                grad_norm_sum += match config.grad_clip {
+                    Some(max_norm) => clip_grad_norm(&mut grads, &vars, max_norm)?,
+                    None => grad_norm(&grads, &vars)?,
+                };
+                epoch_steps += 1;
+                for (_, optimizer) in &mut optimizers {
+                    optimizer.step(&grads)?;
+                }
+                steps += 1;
+                micro_batches = 0;
+            }
+        }
+        let train_loss = loss_sum / train_len as f32;
+        // Stop as soon as training diverges instead of carrying NaNs along.
+        if !train_loss.is_finite() {
+            return Err(TrainError::NonFiniteLoss { epoch }.into());
+        }
+        // Smooth the reported loss; the first epoch starts the average.
+        let train_loss_ema = match metrics.last() {
+            Some(previous) => ema(previous.train_loss_ema, train_loss, config.ema_alpha),
+            None => train_loss,
+        };
+
+        // Score the test data with the same loss as the training data.
+        // Evaluate in mini-batches too when training with them.
+        let test_scores = score_in_chunks(
+            &model,
+            config,
+            &test_votes,
+            &test_results,
+            class_weights.as_ref(),
+            config.batch_size,
+        )?;
+        let test_loss = test_scores.loss;
+        if !test_loss.is_finite() {
+            return Err(TrainError::NonFiniteLoss { epoch }.into());
+        }
+        // Classification is scored by its accuracy, regression by the RMSE.
+        let mut per_class_accuracy = Vec::new();
+        let mut test_confusion = None;
+        let test_rmse = match config.task {
+            Task::Classification => {
+                // Group the predictions by true label to score every class.
+                let mut confusion = ConfusionMatrix::new(model.num_classes()?);
+                for (actual, predicted) in test_results
+                    .to_vec1::<u32>()?
+                    .into_iter()
+                    .zip(test_scores.predictions)
+                {
+                    confusion.record(actual as usize, predicted as usize);
+                }
+                per_class_accuracy = (0..confusion.n_classes())
+                    .map(|class| confusion.recall(class))
+                    .collect();
+                if config.track_confusion {
+                    test_confusion = Some(confusion);
+                }
+                // The test accuracy as a percentage of correct predictions.
+                final_accuracy = test_scores.accuracy;
+                None
+            }
+            Task::Regression => Some(test_loss.sqrt()),
+            Task::MultiLabel { .. } => {
+                // Score the share of label decisions that were right.
+                final_accuracy = test_scores.accuracy;
+                None
+            }
+        };
+
+        // Score the training set too, to compare against the test accuracy.
+        let train_accuracy = if config.compute_train_accuracy {
+            let train_scores = score_in_chunks(
+                &model,
+                config,
+                &train_votes,
+                &train_results,
+                class_weights.as_ref(),
+                config.batch_size,
+            )?;
+            Some(train_scores.accuracy)
+        } else {
+            None
+        };
+
+        // Compute the loss on the validation data, if there is any.
+        let val_loss = match &validation {
+            Some((val_votes, val_results)) => Some(
+                score_in_chunks(
+                    &model,
+                    config,
+                    val_votes,
+                    val_results,
+                    class_weights.as_ref(),
+                    config.batch_size,
+                )?
+                .loss,
+            ),
+            None => None,
+        };
+
+        // Track the best validation (or test) loss and stop once it stops improving.
+        if let Some(early_stopping) = &config.early_stopping {
+            let monitored_loss = val_loss.unwrap_or(test_loss);
+            let improved = match &best {
+                Some((best_loss, _, _)) => monitored_loss < best_loss - early_stopping.min_delta,
+                None => true,
+            };
+            if improved {
+                best = Some((monitored_loss, snapshot(&varmap)?, final_accuracy));
+                epochs_without_improvement = 0;
+            } else {
+                epochs_without_improvement += 1;
+                plateaued = epochs_without_improvement >= early_stopping.patience;
+            }
+        }
+
+        // Record the epoch number, training loss, and test accuracy.
+        let epoch_metrics = EpochMetrics {
+            epoch,
+            train_loss,
+            train_loss_ema,
+            test_loss,
+            val_loss,
+            test_accuracy: final_accuracy,
+            train_accuracy,
+            per_class_accuracy,
+            confusion: test_confusion,
+            test_rmse,
+            learning_rate,
+            grad_norm: grad_norm_sum / epoch_steps as f32,
+        };
+        if config.verbose && config.metrics_format == MetricsFormat::JsonLines {
+            epoch_metrics.write_json_line(&mut std::io::stdout().lock())?;
+        } else if config.verbose {
+            info!(
+                epoch,
+                train_loss = epoch_metrics.train_loss,
+                train_loss_ema = epoch_metrics.train_loss_ema,
+                test_loss = epoch_metrics.test_loss,
+                test_accuracy = epoch_metrics.test_accuracy,
+                train_accuracy = ?epoch_metrics.train_accuracy,
+                per_class_accuracy = ?epoch_metrics.per_class_accuracy,
+                learning_rate = epoch_metrics.learning_rate,
+                grad_norm = epoch_metrics.grad_norm,
+                "epoch finished"
+            );
+        }
+        if config.log_weight_stats {
+            for stats in model.weight_stats()? {
+                info!(
+                    epoch,
+                    tensor = %stats.name,
+                    min = stats.min,
+                    max = stats.max,
+                    mean = stats.mean,
+                    std = stats.std,
+                    "weight stats"
+                );
+            }
+        }
+        // Let the caller observe the epoch and possibly request an early stop.
+        let flow = match on_epoch.as_mut() {
+            Some(on_epoch) => on_epoch(&epoch_metrics),
+            None => ControlFlow::Continue(()),
+        };
+        metrics.push(epoch_metrics);
+
+        // Periodically save the weights and optimizer progress.
+        if let Some(every) = config.checkpoint_every {
+            if every > 0 && epoch % every == 0 {
+                let meta = CheckpointMeta {
+                    epoch,
+                    optimizer_state: OptimizerState {
+                        optimizer: config.optimizer.clone(),
+                        learning_rate,
+                        steps,
+                    },
+                };
+                save_checkpoint(&config.checkpoint_dir, &varmap, &meta)?;
+            }
+        }
+
+        // Once past `min_epochs`, stop early when the test accuracy reaches the target.
+        let accurate_enough = epoch >= config.min_epochs
+            && config.stop_on_accuracy
+            && final_accuracy >= config.target_accuracy;
+        if accurate_enough || flow.is_break() || plateaued {
+            break;
+        }
+    }
+
+    // After a plateau, hand back the weights with the lowest monitored loss instead of the last ones.
+    if plateaued {
+        if let Some((_, weights, accuracy)) = &best {
+            restore(&varmap, weights)?;
+            final_accuracy = *accuracy;
+        }
+    }
+
+    Ok(FitOutcome {
+        epochs: metrics.len(),
+        accuracy: final_accuracy,
+        trained: TrainedModel {
+            model,
+            varmap,
+            metrics,
+        },
+    })
+}
+
+/// Which backend to run the model on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceSpec {
+    /// Use CUDA or Metal when their feature is enabled and a device is
+    /// present, falling back to the CPU.
+    #[default]
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl std::str::FromStr for DeviceSpec {
+    type Err = UtfRnnError;
+
+    /// Parses `auto`, `cpu`, `cuda`, `cuda:<ordinal>`, `metal` or `metal:<ordinal>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (backend, ordinal) = match s.split_once(':') {
+            Some((backend, ordinal)) => {
+                let ordinal = ordinal
+                    .parse::<usize>()
+                    .map_err(|e| UtfRnnError::Device(format!("invalid device {s}: {e}")))?;
+                (backend, Some(ordinal))
+            }
+            None => (s, None),
+        };
+        match (backend.to_lowercase().as_str(), ordinal) {
+            ("auto", None) => Ok(Self::Auto),
+            ("cpu", None) => Ok(Self::Cpu),
+            ("cuda", ordinal) => Ok(Self::Cuda(ordinal.unwrap_or(0))),
+            ("metal", ordinal) => Ok(Self::Metal(ordinal.unwrap_or(0))),
+            _ => Err(UtfRnnError::Device(format!("unknown device: {s}"))),
+        }
+    }
+}
+
+/// Whether utf-rnn was built with the `cuda` feature.
+fn cuda_compiled() -> bool {
+    cfg!(feature = "cuda")
+}
+
+/// Whether utf-rnn was built with the `metal` feature.
+fn metal_compiled() -> bool {
+    cfg!(feature = "metal")
+}
+
+/// Whether the `cuda` feature is enabled and a CUDA device is present.
+fn cuda_enabled() -> bool {
+    cuda_compiled() && candle_core::utils::cuda_is_available()
+}
+
+/// Whether the `metal` feature is enabled and a Metal device is present.
+fn metal_enabled() -> bool {
+    metal_compiled() && candle_core::utils::metal_is_available()
+}
+
+/// Creates the device described by `spec`, failing if the requested backend
+/// was not compiled in or has no device on this machine.
+pub fn resolve_device(spec: &DeviceSpec) -> Result<Device> {
+    match *spec {
+        DeviceSpec::Auto => {
+            if cuda_enabled() {
+                return Ok(Device::new_cuda(0)?);
+            }
+            if metal_enabled() {
+                return Ok(Device::new_metal(0)?);
+            }
+            if cuda_compiled() {
+                warn!("utf-rnn was built with the cuda feature but no CUDA device is available");
+            }
+            if metal_compiled() {
+                warn!("utf-rnn was built with the metal feature but no Metal device is available");
+            }
+            Ok(Device::Cpu)
+        }
+        DeviceSpec::Cpu => Ok(Device::Cpu),
+        DeviceSpec::Cuda(ordinal) => {
+            if !cuda_compiled() {
+                return Err(UtfRnnError::Device(
+                    "CUDA was requested but utf-rnn was built without the cuda feature".to_string(),
+                ));
+            }
+            if !cuda_enabled() {
+                return Err(UtfRnnError::Device(
+                    "CUDA was requested but no CUDA device is available".to_string(),
+                ));
+            }
+            Device::new_cuda(ordinal)
+                .map_err(|e| UtfRnnError::Device(format!("cannot open CUDA device {ordinal}: {e}")))
+        }
+        DeviceSpec::Metal(ordinal) => {
+            if !metal_compiled() {
+                return Err(UtfRnnError::Device(
+                    "Metal was requested but utf-rnn was built without the metal feature"
+                        .to_string(),
+                ));
+            }
+            if !metal_enabled() {
+                return Err(UtfRnnError::Device(
+                    "Metal was requested but no Metal device is available".to_string(),
+                ));
+            }
+            Device::new_metal(ordinal).map_err(|e| {
+                UtfRnnError::Device(format!("cannot open Metal device {ordinal}: {e}"))
+            })
+        }
+    }
+}
@@ -1,183 +1,241 @@
-use anyhow::Result;
-use candle_core::{DType, Device, Tensor, D};
-use candle_nn::{loss, ops, Linear, Module, Optimizer, VarBuilder, VarMap};
-
-pub mod test;
-
-const VOTE_DIM: usize = 2;
-const RESULTS: usize = 1;
-const EPOCHS: usize = 10;
-const LAYER1_OUT_SIZE: usize = 4;
-const LAYER2_OUT_SIZE: usize = 2;
-const LEARNING_RATE: f64 = 0.05;
-
-#[derive(Clone)]
-pub struct Dataset {
-    pub train_votes: Tensor,
-    pub train_results: Tensor,
-    pub test_votes: Tensor,
-    pub test_results: Tensor,
+use std::path::PathBuf;
+
+use candle_core::{Device, Tensor};
+use clap::{Args, Parser, Subcommand};
+use tracing_subscriber::EnvFilter;
+use utf_rnn::predictor::Predictor;
+use utf_rnn::{
+    evaluate, fit, parse_votes, resolve_device, resume_training, train_demo, validate_setup,
+    write_predictions_csv, Dataset, DeviceSpec, TrainConfig,
+};
+
+/// Trains a small perceptron that predicts vote outcomes.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Device to run on: auto, cpu, cuda[:n] or metal[:n]. Defaults to
+    /// `UTF_RNN_DEVICE`, then auto.
+    #[arg(long, global = true)]
+    device: Option<DeviceSpec>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-struct MultiLevelPerceptron {
-    ln1: Linear,
-    ln2: Linear,
-    ln3: Linear,
+#[derive(Subcommand)]
+enum Command {
+    /// Train on a CSV file and save the model.
+    Train(TrainArgs),
+    /// Check a CSV file and config for mistakes without training.
+    Check(CheckArgs),
+    /// Predict the outcome of a row of votes with a saved model.
+    Predict(PredictArgs),
+    /// Train on the built-in sample votes (the default).
+    Example,
 }
 
-impl MultiLevelPerceptron {
-    fn new(vs: VarBuilder) -> Result<Self> {
-        let ln1 = candle_nn::linear(VOTE_DIM, LAYER1_OUT_SIZE, vs.pp("ln1"))?;
-        let ln2 = candle_nn::linear(LAYER1_OUT_SIZE, LAYER2_OUT_SIZE, vs.pp("ln2"))?;
-        let ln3 = candle_nn::linear(LAYER2_OUT_SIZE, RESULTS + 1, vs.pp("ln3"))?;
-        Ok(Self { ln1, ln2, ln3 })
-    }
+#[derive(Args)]
+struct TrainArgs {
+    /// CSV file whose first columns are the votes and whose next column is
+    /// the label.
+    data: PathBuf,
+    /// TOML or JSON file with the hyperparameters; the flags below override
+    /// its values.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Number of vote columns before the label.
+    #[arg(long)]
+    vote_dim: Option<usize>,
+    #[arg(long)]
+    epochs: Option<usize>,
+    #[arg(long)]
+    lr: Option<f64>,
+    /// Directory to write the trained model to: its weights, architecture
+    /// and input standardization.
+    #[arg(long, short)]
+    output: PathBuf,
+    /// Save a checkpoint after every this many epochs.
+    #[arg(long)]
+    checkpoint_every: Option<usize>,
+    #[arg(long)]
+    checkpoint_dir: Option<PathBuf>,
+    /// Continue from the latest checkpoint in `--checkpoint-dir`.
+    #[arg(long)]
+    resume: bool,
+}
 
-    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
-        let xs = self.ln1.forward(xs)?;
-        let xs = xs.relu()?;
-        let xs = self.ln2.forward(&xs)?;
-        let xs = xs.relu()?;
-        self.ln3.forward(&xs).map_err(Into::into)
-    }
+#[derive(Args)]
+struct CheckArgs {
+    /// CSV file laid out as for `train`.
+    data: PathBuf,
+    /// TOML or JSON file with the hyperparameters.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Number of vote columns before the label.
+    #[arg(long)]
+    vote_dim: Option<usize>,
 }
 
-fn train(m: Dataset, dev: &Device) -> anyhow::Result<MultiLevelPerceptron> {
-    // Move the training results tensor to the specified device (e.g., GPU or CPU).
-    let train_results = m.train_results.to_device(dev)?;
-    // Move the training votes tensor to the specified device.
-    let train_votes = m.train_votes.to_device(dev)?;
-
-    // Create a variable map to store model parameters.
-    let varmap = VarMap::new();
-    // Create a variable builder from the variable map, specifying the data type and device.
-    let vs = VarBuilder::from_varmap(&varmap, DType::F32, dev);
-    // Initialize the multi-level perceptron model with the variable builder.
-    let model = MultiLevelPerceptron::new(vs.clone())?;
-
-    // Initialize the SGD optimizer with the model's parameters and the learning rate.
-    let mut sgd = candle_nn::SGD::new(varmap.all_vars(), LEARNING_RATE)?;
-
-    // Move the test votes tensor to the specified device.
-    let test_votes = m.test_votes.to_device(dev)?;
-    // Move the test results tensor to the specified device.
-    let test_results = m.test_results.to_device(dev)?;
-
-    // Variable to store the final accuracy of the model.
-    let mut final_accuracy: f32 = 0.0;
-
-    // Training loop for the specified number of epochs.
-    for epoch in 1..EPOCHS + 1 {
-        // Forward pass: compute the logits (raw predictions) for the training data.
-        let logits = model.forward(&train_votes)?;
-        // Apply log softmax to the logits to get log probabilities.
-        let log_sm = ops::log_softmax(&logits, D::Minus1)?;
-        // Compute the negative log-likelihood loss between the log probabilities and the true labels.
-        let loss = loss::nll(&log_sm, &train_results)?;
-
-        // Perform a backward step to update the model parameters using SGD.
-        sgd.backward_step(&loss)?;
-
-        // Forward pass: compute the logits for the test data.
-        let test_logits = model.forward(&test_votes)?;
-        // Compute the number of correct predictions by comparing the predicted labels with the true labels.
-        let sum_ok = test_logits
-            .argmax(D::Minus1)?
-            .eq(&test_results)?
-            .to_dtype(DType::F32)?
-            .sum_all()?
-            .to_scalar::<f32>()?;
-        // Calculate the test accuracy as a percentage.
-        let test_accuracy = sum_ok / test_results.dims1()? as f32;
-        final_accuracy = 100. * test_accuracy;
-
-        // Print the epoch number, training loss, and test accuracy.
-        println!(
-            "Epoch: {epoch:3} Train loss: {:8.5} Test accuracy: {:5.2}%",
-            loss.to_scalar::<f32>()?,
-            final_accuracy
-        );
+#[derive(Args)]
+struct PredictArgs {
+    /// Model directory written by `train`.
+    #[arg(long)]
+    model: PathBuf,
+    /// Also write the votes, prediction and probabilities to this CSV file.
+    #[arg(long)]
+    out: Option<PathBuf>,
+    /// The votes to classify as one comma-separated line, e.g. `13,22`.
+    #[arg(long, conflicts_with = "votes")]
+    input: Option<String>,
+    /// The votes to classify, as many as the model was trained on. Without
+    /// them or `--input` a comma-separated line of votes is read from stdin.
+    votes: Vec<f32>,
+}
+
+fn main() -> anyhow::Result<()> {
+    // Log at `info` unless `RUST_LOG` says otherwise.
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let cli = Cli::parse();
+    // Without `--device` the device can be overridden with e.g. `UTF_RNN_DEVICE=cpu`.
+    let spec = match (cli.device, std::env::var("UTF_RNN_DEVICE")) {
+        (Some(spec), _) => spec,
+        (None, Ok(spec)) => spec.parse()?,
+        (None, Err(_)) => DeviceSpec::Auto,
+    };
+    let dev = resolve_device(&spec)?;
 
-        // If the test accuracy reaches 100%, stop training early.
-        if final_accuracy == 100.0 {
-            break;
-        }
+    match cli.command.unwrap_or(Command::Example) {
+        Command::Train(args) => run_train(args, &dev),
+        Command::Check(args) => run_check(args, &dev),
+        Command::Predict(args) => run_predict(args, &dev),
+        Command::Example => run_example(&dev),
+    }
+}
+
+fn run_train(args: TrainArgs, dev: &Device) -> anyhow::Result<()> {
+    let mut config = match &args.config {
+        Some(path) => TrainConfig::from_file(path)?,
+        None => TrainConfig::default(),
+    };
+    if let Some(vote_dim) = args.vote_dim {
+        config.vote_dim = vote_dim;
+    }
+    if let Some(epochs) = args.epochs {
+        config.epochs = epochs;
+    }
+    if let Some(lr) = args.lr {
+        config.learning_rate = lr;
+    }
+    if args.checkpoint_every.is_some() {
+        config.checkpoint_every = args.checkpoint_every;
+    }
+    if let Some(checkpoint_dir) = args.checkpoint_dir {
+        config.checkpoint_dir = checkpoint_dir;
     }
+    let feature_cols: Vec<usize> = (0..config.vote_dim).collect();
+    let m = Dataset::from_csv(&args.data, &feature_cols, config.vote_dim, dev)?;
 
-    // If the final accuracy is less than 100%, return an error indicating the model is not trained well enough.
-    if final_accuracy < 100.0 {
-        Err(anyhow::Error::msg("The model is not trained well enough."))
+    let trained = if args.resume {
+        resume_training(&config.checkpoint_dir, m, &config, dev)?
     } else {
-        // Otherwise, return the trained model.
-        Ok(model)
+        fit(m, &config, dev, None)?.trained
+    };
+    if let Some(last) = trained.metrics.last() {
+        println!(
+            "Trained up to epoch {} (test accuracy: {:5.2}%).",
+            last.epoch, last.test_accuracy
+        );
     }
+    Predictor::new(trained, &config, None)?.save(&args.output)?;
+    println!("Saved the model to {}.", args.output.display());
+
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let dev = Device::cuda_if_available(0)?;
-
-    let train_votes_vec: Vec<u32> =
-        vec![15, 10, 10, 15, 5, 12, 30, 20, 16, 12, 13, 25, 6, 14, 31, 21];
-    let train_votes_tensor = Tensor::from_vec(
-        train_votes_vec.clone(),
-        (train_votes_vec.len() / VOTE_DIM, VOTE_DIM),
-        &dev,
-    )?
-    .to_dtype(DType::F32)?;
-
-    let train_results_vec: Vec<u32> = vec![1, 0, 0, 1, 1, 0, 0, 1];
-    let train_results_tensor =
-        Tensor::from_vec(train_results_vec, train_votes_vec.len() / VOTE_DIM, &dev)?;
-
-    let test_votes_vec: Vec<u32> = vec![13, 9, 8, 14, 3, 10];
-    let test_votes_tensor = Tensor::from_vec(
-        test_votes_vec.clone(),
-        (test_votes_vec.len() / VOTE_DIM, VOTE_DIM),
-        &dev,
-    )?
-    .to_dtype(DType::F32)?;
-
-    let test_results_vec: Vec<u32> = vec![1, 0, 0];
-    let test_results_tensor =
-        Tensor::from_vec(test_results_vec.clone(), test_results_vec.len(), &dev)?;
-
-    let m = Dataset {
-        train_votes: train_votes_tensor,
-        train_results: train_results_tensor,
-        test_votes: test_votes_tensor,
-        test_results: test_results_tensor,
+fn run_check(args: CheckArgs, dev: &Device) -> anyhow::Result<()> {
+    let mut config = match &args.config {
+        Some(path) => TrainConfig::from_file(path)?,
+        None => TrainConfig::default(),
     };
+    if let Some(vote_dim) = args.vote_dim {
+        config.vote_dim = vote_dim;
+    }
+    let feature_cols: Vec<usize> = (0..config.vote_dim).collect();
+    let m = Dataset::from_csv(&args.data, &feature_cols, config.vote_dim, dev)?;
+
+    validate_setup(&m, &config)?;
+    println!(
+        "{} is ready to train ({} training and {} test rows).",
+        args.data.display(),
+        m.train_votes.dim(0)?,
+        m.test_votes.dim(0)?
+    );
+
+    Ok(())
+}
 
-    let trained_model: MultiLevelPerceptron;
-    loop {
-        println!("Trying to train neural network.");
-        match train(m.clone(), &dev) {
-            Ok(model) => {
-                trained_model = model;
-                break;
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-                continue;
-            }
-        }
+fn run_predict(args: PredictArgs, dev: &Device) -> anyhow::Result<()> {
+    let votes = if let Some(input) = &args.input {
+        parse_votes(input, dev)?
+    } else if args.votes.is_empty() {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        parse_votes(&line, dev)?
+    } else {
+        Tensor::from_slice(&args.votes, (1, args.votes.len()), dev)?
+    };
+    // The directory records the architecture the model was trained with.
+    let predictor = Predictor::load(&args.model, dev)?;
+    let model = predictor.model();
+
+    let result = model.predict(&votes)?[0];
+    let probabilities = model.predict_proba(&votes)?;
+    let row = probabilities.get(0)?.to_vec1::<f32>()?;
+    println!("votes: {:?}", votes.get(0)?.to_vec1::<f32>()?);
+    println!("prediction_result: {:?}", result);
+    println!("prediction_confidence: {:?}", row[result as usize]);
+    println!("prediction_probabilities: {:?}", row);
+    if let Some(out) = &args.out {
+        write_predictions_csv(out, &votes, &[result], Some(&probabilities))?;
+        println!("Wrote the prediction to {}.", out.display());
     }
 
-    let real_world_votes: Vec<u32> = vec![13, 22];
+    Ok(())
+}
+
+/// The original demo: trains on the hardcoded sample votes and predicts one
+/// real world vote pair.
+fn run_example(dev: &Device) -> anyhow::Result<()> {
+    let config = TrainConfig {
+        normalize: true,
+        ..Default::default()
+    };
 
-    let tensor_test_votes =
-        Tensor::from_vec(real_world_votes.clone(), (1, VOTE_DIM), &dev)?.to_dtype(DType::F32)?;
+    let (m, trained_model) = train_demo(&config, dev)?;
+    let confusion = evaluate(&trained_model, &m.test_votes, &m.test_results)?;
 
-    let final_result = trained_model.forward(&tensor_test_votes)?;
+    let real_world_votes: Vec<f32> = vec![13., 22.];
 
-    let result = final_result
-        .argmax(D::Minus1)?
-        .to_dtype(DType::F32)?
-        .get(0)
-        .map(|x| x.to_scalar::<f32>())??;
+    let result = trained_model.predict_outcome(&real_world_votes)?;
+    let probabilities = trained_model
+        .predict_proba(&Tensor::from_slice(
+            &real_world_votes,
+            (1, config.vote_dim),
+            dev,
+        )?)?
+        .get(0)?
+        .to_vec1::<f32>()?;
     println!("real_life_votes: {:?}", real_world_votes);
     println!("neural_network_prediction_result: {:?}", result);
+    println!(
+        "neural_network_prediction_probabilities: {:?}",
+        probabilities
+    );
+    println!("test_confusion_matrix (rows: actual, columns: predicted):\n{confusion}");
 
     Ok(())
 }
@@ -0,0 +1,258 @@
+//! A minimal ONNX writer for [`MultiLevelPerceptron`], encoding the protobuf
+//! messages of `onnx.proto` by hand so no protobuf dependency is needed.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use candle_core::{DType, Tensor, Var};
+use candle_nn::VarMap;
+
+use crate::error::{Result, UtfRnnError};
+use crate::{Activation, MultiLevelPerceptron};
+
+/// Name of the graph input holding the `(n, input_dim)` votes.
+pub const ONNX_INPUT: &str = "votes";
+/// Name of the graph output holding the `(n, num_classes)` logits.
+pub const ONNX_OUTPUT: &str = "logits";
+
+// `TensorProto.DataType` values.
+const FLOAT: i64 = 1;
+const INT64: i64 = 7;
+// `AttributeProto.AttributeType` values.
+const ATTR_INT: i64 = 2;
+const ATTR_STRING: i64 = 3;
+
+/// Appends `value` as a base-128 varint.
+fn varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn int_field(buf: &mut Vec<u8>, field: u64, value: i64) {
+    varint(buf, field << 3);
+    varint(buf, value as u64);
+}
+
+fn bytes_field(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buf, field << 3 | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// A `TensorProto` with its values as little-endian `raw_data`.
+fn tensor_proto(name: &str, tensor: &Tensor) -> Result<Vec<u8>> {
+    let values = tensor
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let mut buf = Vec::new();
+    for &dim in tensor.dims() {
+        int_field(&mut buf, 1, dim as i64);
+    }
+    int_field(&mut buf, 2, FLOAT);
+    bytes_field(&mut buf, 8, name.as_bytes());
+    let raw: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    bytes_field(&mut buf, 9, &raw);
+    Ok(buf)
+}
+
+/// A `ValueInfoProto` of a `(n, width)` tensor with a symbolic batch size.
+fn value_info(name: &str, elem_type: i64, width: usize) -> Vec<u8> {
+    let mut batch = Vec::new();
+    bytes_field(&mut batch, 2, b"n");
+    let mut columns = Vec::new();
+    int_field(&mut columns, 1, width as i64);
+    let mut shape = Vec::new();
+    bytes_field(&mut shape, 1, &batch);
+    bytes_field(&mut shape, 1, &columns);
+
+    let mut tensor_type = Vec::new();
+    int_field(&mut tensor_type, 1, elem_type);
+    bytes_field(&mut tensor_type, 2, &shape);
+    let mut type_proto = Vec::new();
+    bytes_field(&mut type_proto, 1, &tensor_type);
+
+    let mut buf = Vec::new();
+    bytes_field(&mut buf, 1, name.as_bytes());
+    bytes_field(&mut buf, 2, &type_proto);
+    buf
+}
+
+fn int_attribute(name: &str, value: i64) -> Vec<u8> {
+    let mut buf = Vec::new();
+    bytes_field(&mut buf, 1, name.as_bytes());
+    int_field(&mut buf, 3, value);
+    int_field(&mut buf, 20, ATTR_INT);
+    buf
+}
+
+fn string_attribute(name: &str, value: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    bytes_field(&mut buf, 1, name.as_bytes());
+    bytes_field(&mut buf, 4, value.as_bytes());
+    int_field(&mut buf, 20, ATTR_STRING);
+    buf
+}
+
+/// The nodes of the graph under construction and the name of the latest
+/// intermediate result.
+struct Graph {
+    nodes: Vec<Vec<u8>>,
+    current: String,
+}
+
+impl Graph {
+    /// Adds an `op_type` node reading `inputs`, whose output becomes the new
+    /// `current`.
+    fn push(&mut self, op_type: &str, inputs: &[&str], output: &str, attributes: Vec<Vec<u8>>) {
+        let mut buf = Vec::new();
+        for input in inputs {
+            bytes_field(&mut buf, 1, input.as_bytes());
+        }
+        bytes_field(&mut buf, 2, output.as_bytes());
+        bytes_field(&mut buf, 3, output.as_bytes());
+        bytes_field(&mut buf, 4, op_type.as_bytes());
+        for attribute in attributes {
+            bytes_field(&mut buf, 5, &attribute);
+        }
+        self.nodes.push(buf);
+        self.current = output.to_string();
+    }
+}
+
+/// The `TensorProto` of the variable `name` of `vars`.
+fn var_initializer(vars: &HashMap<String, Var>, name: &str) -> Result<Vec<u8>> {
+    let var = vars.get(name).ok_or_else(|| UtfRnnError::MissingTensor {
+        path: "the varmap".to_string(),
+        name: name.to_string(),
+    })?;
+    tensor_proto(name, var.as_tensor())
+}
+
+impl MultiLevelPerceptron {
+    /// Writes the model as an ONNX graph to `path`, reading the weights from
+    /// `varmap`. The graph maps the `(n, input_dim)` input `votes` (`int64`
+    /// category ids for categorical inputs) to the `(n, num_classes)` output
+    /// `logits` with one `Gemm` per layer, the activation between them, and
+    /// the standardization and embedding lookup when the model has them.
+    /// Dropout is left out, as in [`MultiLevelPerceptron::forward`].
+    pub fn export_onnx(&self, varmap: &VarMap, path: &Path) -> Result<()> {
+        let vars = varmap.data().lock().unwrap();
+        let mut initializers = Vec::new();
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            current: ONNX_INPUT.to_string(),
+        };
+
+        let input_type = if self.embedding.is_some() {
+            initializers.push(var_initializer(&vars, "embedding.weight")?);
+            // (n, vote_dim) ids -> (n, vote_dim, embed_dim) -> (n, vote_dim * embed_dim)
+            graph.push(
+                "Gather",
+                &["embedding.weight", ONNX_INPUT],
+                "embedded",
+                vec![int_attribute("axis", 0)],
+            );
+            graph.push(
+                "Flatten",
+                &["embedded"],
+                "flattened",
+                vec![int_attribute("axis", 1)],
+            );
+            INT64
+        } else {
+            if let Some(standardizer) = &self.standardizer {
+                initializers.push(tensor_proto("standardizer.mean", &standardizer.mean)?);
+                initializers.push(tensor_proto("standardizer.std", &standardizer.std)?);
+                graph.push(
+                    "Sub",
+                    &[ONNX_INPUT, "standardizer.mean"],
+                    "centered",
+                    Vec::new(),
+                );
+                graph.push(
+                    "Div",
+                    &["centered", "standardizer.std"],
+                    "standardized",
+                    Vec::new(),
+                );
+            }
+            FLOAT
+        };
+
+        for (i, layer) in self.layers.iter().enumerate() {
+            let name = format!("ln{}", i + 1);
+            let mut inputs = vec![graph.current.clone(), format!("{name}.weight")];
+            if layer.bias().is_some() {
+                inputs.push(format!("{name}.bias"));
+            }
+            for input in &inputs[1..] {
+                initializers.push(var_initializer(&vars, input)?);
+            }
+            let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+            let last = i + 1 == self.layers.len();
+            let output = if last { ONNX_OUTPUT } else { name.as_str() };
+            // candle stores `(out, in)` weights and computes `x W^T + b`.
+            graph.push("Gemm", &inputs, output, vec![int_attribute("transB", 1)]);
+            if !last {
+                let op_type = activation_op(self.activation);
+                let attributes = match self.activation {
+                    // candle's GELU is the tanh approximation.
+                    Activation::Gelu => vec![string_attribute("approximate", "tanh")],
+                    _ => Vec::new(),
+                };
+                let input = graph.current.clone();
+                let output = format!("{name}_{}", op_type.to_lowercase());
+                graph.push(op_type, &[input.as_str()], &output, attributes);
+            }
+        }
+
+        let mut graph_buf = Vec::new();
+        for node in &graph.nodes {
+            bytes_field(&mut graph_buf, 1, node);
+        }
+        bytes_field(&mut graph_buf, 2, b"utf-rnn");
+        for initializer in &initializers {
+            bytes_field(&mut graph_buf, 5, initializer);
+        }
+        bytes_field(
+            &mut graph_buf,
+            11,
+            &value_info(ONNX_INPUT, input_type, self.input_dim),
+        );
+        bytes_field(
+            &mut graph_buf,
+            12,
+            &value_info(ONNX_OUTPUT, FLOAT, self.num_classes()?),
+        );
+
+        // `Gelu` only exists from opset 20 on, which needs IR version 9.
+        let (ir_version, opset) = match self.activation {
+            Activation::Gelu => (9, 20),
+            _ => (7, 13),
+        };
+        let mut opset_import = Vec::new();
+        int_field(&mut opset_import, 2, opset);
+        let mut model = Vec::new();
+        int_field(&mut model, 1, ir_version);
+        bytes_field(&mut model, 2, b"utf-rnn");
+        bytes_field(&mut model, 7, &graph_buf);
+        bytes_field(&mut model, 8, &opset_import);
+        fs::write(path, model)?;
+        Ok(())
+    }
+}
+
+/// ONNX operator of `activation`.
+fn activation_op(activation: Activation) -> &'static str {
+    match activation {
+        Activation::Relu => "Relu",
+        Activation::Gelu => "Gelu",
+        Activation::Tanh => "Tanh",
+        Activation::Sigmoid => "Sigmoid",
+    }
+}
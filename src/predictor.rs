@@ -0,0 +1,205 @@
+use std::fs;
+use std::path::Path;
+
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, UtfRnnError};
+use crate::tokenizer::CharVocab;
+use crate::{
+    fit, load_weights, Activation, Dataset, InputMode, MultiLevelPerceptron, Standardizer, Task,
+    TrainConfig, TrainedModel,
+};
+
+const WEIGHTS_FILE: &str = "weights.safetensors";
+const SPEC_FILE: &str = "predictor.json";
+
+/// Everything besides the weights needed to rebuild a [`Predictor`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PredictorSpec {
+    vote_dim: usize,
+    #[serde(default)]
+    input_mode: InputMode,
+    #[serde(default)]
+    task: Task,
+    num_classes: usize,
+    hidden_sizes: Vec<usize>,
+    activation: Activation,
+    #[serde(default = "default_bias")]
+    bias: bool,
+    /// Per-feature `(mean, std)` of the input standardization.
+    standardizer: Option<(Vec<f32>, Vec<f32>)>,
+    /// The vocabulary characters in id order.
+    vocab: Option<String>,
+}
+
+/// Specs written before the `bias` option always had biases.
+fn default_bias() -> bool {
+    true
+}
+
+/// A trained perceptron bundled with its input preprocessing, persisted as
+/// one directory.
+pub struct Predictor {
+    model: MultiLevelPerceptron,
+    varmap: VarMap,
+    spec: PredictorSpec,
+    vocab: Option<CharVocab>,
+}
+
+impl Predictor {
+    /// Wraps a model trained with `config`, optionally carrying the
+    /// vocabulary its inputs were encoded with.
+    pub fn new(
+        trained: TrainedModel,
+        config: &TrainConfig,
+        vocab: Option<CharVocab>,
+    ) -> Result<Self> {
+        let standardizer = match &trained.model.standardizer {
+            Some(standardizer) => Some((
+                standardizer
+                    .mean
+                    .flatten_all()?
+                    .to_dtype(DType::F32)?
+                    .to_vec1::<f32>()?,
+                standardizer
+                    .std
+                    .flatten_all()?
+                    .to_dtype(DType::F32)?
+                    .to_vec1::<f32>()?,
+            )),
+            None => None,
+        };
+        let spec = PredictorSpec {
+            vote_dim: trained.model.input_dim(),
+            input_mode: config.input_mode,
+            task: config.task,
+            num_classes: config.num_classes,
+            hidden_sizes: config.hidden_sizes.clone(),
+            activation: config.activation,
+            bias: config.bias,
+            standardizer,
+            vocab: vocab.as_ref().map(|vocab| vocab.chars().iter().collect()),
+        };
+        Ok(Self {
+            model: trained.model,
+            varmap: trained.varmap,
+            spec,
+            vocab,
+        })
+    }
+
+    /// Trains a classifier on plain rows of votes and their class labels,
+    /// without building any tensors, holding out the last rows for testing
+    /// like [`Dataset::from_rows`]. Unlike `train` it keeps the model even
+    /// below full test accuracy.
+    pub fn train_rows(
+        rows: &[Vec<f32>],
+        labels: &[u32],
+        config: &TrainConfig,
+        dev: &Device,
+    ) -> Result<Self> {
+        let dataset = Dataset::from_rows(rows, labels, dev)?;
+        let trained = fit(dataset, config, dev, None)?.trained;
+        Self::new(trained, config, None)
+    }
+
+    /// Classifies plain rows of raw votes, like [`Predictor::predict`] one
+    /// row at a time, in a single forward pass.
+    pub fn predict_rows(&self, rows: &[Vec<f32>]) -> Result<Vec<u32>> {
+        Ok(self
+            .model
+            .predict_batch(rows)?
+            .into_iter()
+            .map(|(class, _)| class)
+            .collect())
+    }
+
+    pub fn model(&self) -> &MultiLevelPerceptron {
+        &self.model
+    }
+
+    pub fn vocab(&self) -> Option<&CharVocab> {
+        self.vocab.as_ref()
+    }
+
+    /// Classifies one row of raw, unnormalized votes, returning the class
+    /// and its softmax probability.
+    pub fn predict(&self, votes: &[f32]) -> Result<(u32, f32)> {
+        let device = self.model.layers[0].weight().device();
+        let votes = Tensor::from_slice(votes, (1, votes.len()), device)?;
+        let probabilities = self.model.predict_proba(&votes)?.get(0)?.to_vec1::<f32>()?;
+        let (class, confidence) = probabilities
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .expect("at least one class");
+        Ok((class as u32, *confidence))
+    }
+
+    /// Classifies rows of raw vote counts, e.g. straight from a tally. The
+    /// model casts them to its dtype and standardizes them like it was
+    /// trained to.
+    pub fn predict_u32(&self, rows: &[Vec<u32>]) -> Result<Vec<u32>> {
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let vote_dim = self.model.input_dim();
+        if let Some(row) = rows.iter().find(|row| row.len() != vote_dim) {
+            return Err(UtfRnnError::ShapeMismatch {
+                name: "vote row".to_string(),
+                expected: vec![vote_dim],
+                got: vec![row.len()],
+            });
+        }
+        let device = self.model.layers[0].weight().device();
+        let votes = Tensor::from_vec(rows.concat(), (rows.len(), vote_dim), device)?;
+        self.model.predict(&votes)
+    }
+
+    /// Writes the weights and preprocessing into `dir`, creating it if needed.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        self.model.save(&self.varmap, &dir.join(WEIGHTS_FILE))?;
+        fs::write(
+            dir.join(SPEC_FILE),
+            serde_json::to_string_pretty(&self.spec)?,
+        )?;
+        Ok(())
+    }
+
+    /// Restores a predictor written by [`Predictor::save`].
+    pub fn load(dir: &Path, dev: &Device) -> Result<Self> {
+        let spec: PredictorSpec = serde_json::from_str(&fs::read_to_string(dir.join(SPEC_FILE))?)?;
+        let config = TrainConfig {
+            vote_dim: spec.vote_dim,
+            input_mode: spec.input_mode,
+            task: spec.task,
+            num_classes: spec.num_classes,
+            hidden_sizes: spec.hidden_sizes.clone(),
+            activation: spec.activation,
+            bias: spec.bias,
+            ..Default::default()
+        };
+
+        let varmap = VarMap::new();
+        let mut model =
+            MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, dev), &config)?;
+        load_weights(&varmap, &dir.join(WEIGHTS_FILE), dev)?;
+        if let Some((mean, std)) = &spec.standardizer {
+            model.standardizer = Some(Standardizer {
+                mean: Tensor::from_slice(mean, (1, mean.len()), dev)?,
+                std: Tensor::from_slice(std, (1, std.len()), dev)?,
+            });
+        }
+        let vocab = spec.vocab.as_deref().map(CharVocab::new);
+
+        Ok(Self {
+            model,
+            varmap,
+            spec,
+            vocab,
+        })
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use candle_core::{Device, Tensor};
+
+use crate::error::{Result, UtfRnnError};
+use crate::tokenizer::CharVocab;
+
+/// Bytes read from the file whenever more characters are needed.
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Cuts a text file into the same next-character windows as
+/// [`Dataset::from_text_file`](crate::Dataset::from_text_file), but reads it
+/// in chunks and yields `(inputs, targets)` batches of up to `batch_size`
+/// `(rows, seq_len)` windows, so the corpus never has to fit in memory.
+///
+/// Characters missing from the vocabulary are skipped.
+pub struct SequenceStream {
+    reader: BufReader<File>,
+    vocab: CharVocab,
+    seq_len: usize,
+    batch_size: usize,
+    chunk_size: usize,
+    device: Device,
+    /// Encoded characters not yet consumed by a window start.
+    ids: Vec<u32>,
+    /// Trailing bytes of an incomplete UTF-8 character from the last chunk.
+    pending: Vec<u8>,
+    /// Set once the file is exhausted or reading it failed.
+    done: bool,
+}
+
+impl SequenceStream {
+    pub fn open(
+        path: &Path,
+        vocab: CharVocab,
+        seq_len: usize,
+        batch_size: usize,
+        dev: &Device,
+    ) -> Result<Self> {
+        if seq_len == 0 || batch_size == 0 {
+            return Err(UtfRnnError::InvalidInput(format!(
+                "seq_len and batch_size must be at least 1, got {seq_len} and {batch_size}"
+            )));
+        }
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+            vocab,
+            seq_len,
+            batch_size,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            device: dev.clone(),
+            ids: Vec::new(),
+            pending: Vec::new(),
+            done: false,
+        })
+    }
+
+    /// Number of bytes read from the file at a time.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = bytes.max(1);
+        self
+    }
+
+    pub fn vocab(&self) -> &CharVocab {
+        &self.vocab
+    }
+
+    /// Number of complete windows in the buffered characters.
+    fn windows(&self) -> usize {
+        self.ids.len().saturating_sub(self.seq_len)
+    }
+
+    /// Reads and encodes the next chunk, returning `false` at the end of the
+    /// file.
+    fn fill(&mut self) -> Result<bool> {
+        let mut chunk = vec![0; self.chunk_size];
+        let read = self.reader.read(&mut chunk)?;
+        if read == 0 {
+            if !self.pending.is_empty() {
+                return Err(UtfRnnError::InvalidInput(
+                    "the file ends inside a UTF-8 character".to_string(),
+                ));
+            }
+            return Ok(false);
+        }
+        self.pending.extend_from_slice(&chunk[..read]);
+        // A character split across chunks stays pending until the next read.
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => {
+                return Err(UtfRnnError::InvalidInput(format!(
+                    "the file is not valid UTF-8: {e}"
+                )))
+            }
+        };
+        let text = std::str::from_utf8(&self.pending[..valid]).expect("validated above");
+        self.ids.extend(self.vocab.encode(text));
+        self.pending.drain(..valid);
+        Ok(true)
+    }
+
+    /// Cuts the next batch out of the buffered characters, reading more as
+    /// needed.
+    fn next_batch(&mut self) -> Result<Option<(Tensor, Tensor)>> {
+        while self.windows() < self.batch_size {
+            if !self.fill()? {
+                break;
+            }
+        }
+        let rows = self.windows().min(self.batch_size);
+        if rows == 0 {
+            return Ok(None);
+        }
+        let seq_len = self.seq_len;
+        let inputs: Vec<u32> = (0..rows)
+            .flat_map(|i| self.ids[i..i + seq_len].iter().copied())
+            .collect();
+        let targets: Vec<u32> = (0..rows)
+            .flat_map(|i| self.ids[i + 1..i + 1 + seq_len].iter().copied())
+            .collect();
+        // Keep the characters later windows still start from.
+        self.ids.drain(..rows);
+        Ok(Some((
+            Tensor::from_vec(inputs, (rows, seq_len), &self.device)?,
+            Tensor::from_vec(targets, (rows, seq_len), &self.device)?,
+        )))
+    }
+}
+
+impl Iterator for SequenceStream {
+    type Item = Result<(Tensor, Tensor)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_batch() {
+            Ok(Some(batch)) => Some(Ok(batch)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
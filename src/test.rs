@@ -1,73 +1,3590 @@
+use std::ops::ControlFlow;
+
 use candle_core::{DType, Device, Tensor, D};
-use candle_nn::{loss, ops, Linear, Module, Optimizer, VarBuilder, VarMap};
+use candle_nn::{loss, ops, Linear, Module, VarBuilder, VarMap};
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::char_rnn::{self, perplexity, CharRnn};
+use crate::char_transformer::CharTransformer;
+use crate::error::{TrainError, UtfRnnError};
+use crate::predictor::Predictor;
+use crate::stream::SequenceStream;
+use crate::tokenizer::CharVocab;
+use crate::{
+    apply_activation, cross_validate, decayed_vars, decision_grid, ema, evaluate, evaluate_batched,
+    evaluate_multilabel, fit, group_optimizers, init_seeded, inverse_frequency_weights,
+    parse_votes, resolve_device, resume_training, sample_dataset, score_in_chunks,
+    search_architectures, shuffle_rows, train, train_demo, train_until_converged,
+    train_with_varmap, unlearnable_dataset, validate_setup, write_predictions_csv, Activation,
+    ConfusionMatrix, Dataset, DeviceSpec, EarlyStopping, EpochMetrics, Init, InputMode,
+    JsonLinesObserver, Loss, LrSchedule, MultiLevelPerceptron, Optimizer, PerceptronBuilder,
+    SilentObserver, Standardizer, Task, TrainConfig, TrainObserver, VoteOutcome, WeightedSampler,
+    MAX_ATTEMPTS, VOTE_DIM,
+};
 
-use crate::{train, Dataset, MultiLevelPerceptron, VOTE_DIM};
+/// Trains once with every seed in `seeds` and returns the total number of
+/// epochs the runs took to converge, counting all `config.epochs` of a run
+/// that never does.
+fn epochs_to_converge(
+    m: &Dataset,
+    config: &TrainConfig,
+    seeds: std::ops::Range<u64>,
+    dev: &Device,
+) -> anyhow::Result<usize> {
+    let mut epochs = 0;
+    for seed in seeds {
+        let config = TrainConfig {
+            seed: Some(seed),
+            ..config.clone()
+        };
+        epochs += fit(m.clone(), &config, dev, None)?.epochs;
+    }
+    Ok(epochs)
+}
 
-#[tokio::test]
-async fn simplified() -> anyhow::Result<()> {
+#[test]
+fn simplified() -> anyhow::Result<()> {
     let dev = Device::cuda_if_available(0)?;
+    let config = TrainConfig::default();
 
-    let train_votes_vec: Vec<u32> =
-        vec![15, 10, 10, 15, 5, 12, 30, 20, 16, 12, 13, 25, 6, 14, 31, 21];
-    let train_votes_tensor = Tensor::from_vec(
-        train_votes_vec.clone(),
-        (train_votes_vec.len() / VOTE_DIM, VOTE_DIM),
-        &dev,
-    )?
-    .to_dtype(DType::F32)?;
+    let (_, trained_model) = train_demo(&config, &dev)?;
 
-    let train_results_vec: Vec<u32> = vec![1, 0, 0, 1, 1, 0, 0, 1];
-    let train_results_tensor =
-        Tensor::from_vec(train_results_vec, train_votes_vec.len() / VOTE_DIM, &dev)?;
+    let real_world_votes: Vec<f32> = vec![13., 22.];
 
-    let test_votes_vec: Vec<u32> = vec![13, 9, 8, 14, 3, 10];
-    let test_votes_tensor = Tensor::from_vec(
-        test_votes_vec.clone(),
-        (test_votes_vec.len() / VOTE_DIM, VOTE_DIM),
-        &dev,
-    )?
-    .to_dtype(DType::F32)?;
+    let result = trained_model.predict_one(&real_world_votes)?;
+    println!("real_life_votes: {:?}", real_world_votes);
+    println!("neural_network_prediction_result: {:?}", result);
+
+    Ok(())
+}
+
+/// Converges in a single seeded run on the CPU, without the retry loop of
+/// `simplified`, so it is a stable regression test.
+#[test]
+fn seeded_cpu_training_converges_in_one_attempt() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        hidden_sizes: vec![8],
+        activation: Activation::Tanh,
+        optimizer: Optimizer::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.0,
+        },
+        epochs: 500,
+        normalize: true,
+        seed: Some(7),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    // A single attempt; `train` fails unless the test accuracy reaches 100%.
+    let trained = train(m, &config, &dev, &mut SilentObserver)?;
+    assert_eq!(trained.metrics.last().unwrap().test_accuracy, 100.0);
+    // A motion passes when it has clearly more yes than no votes.
+    assert_eq!(trained.model.predict_one(&[31., 10.])?, 1);
+    assert_eq!(trained.model.predict_one(&[5., 25.])?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn adamw_converges_faster_than_sgd() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let sgd_config = TrainConfig {
+        epochs: 200,
+        verbose: false,
+        ..Default::default()
+    };
+    let adamw_config = TrainConfig {
+        optimizer: Optimizer::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.01,
+        },
+        ..sgd_config.clone()
+    };
+    let m = sample_dataset(&dev)?;
+
+    // Sum over several seeded runs, the same seeds for both optimizers, to
+    // smooth out the initialization while staying reproducible.
+    let sgd_epochs = epochs_to_converge(&m, &sgd_config, 0..5, &dev)?;
+    let adamw_epochs = epochs_to_converge(&m, &adamw_config, 0..5, &dev)?;
+    assert!(
+        adamw_epochs < sgd_epochs,
+        "sgd: {sgd_epochs} epochs, adamw: {adamw_epochs} epochs"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn gives_up_after_max_attempts() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    // Without any epochs the model can never reach full accuracy.
+    let config = TrainConfig {
+        epochs: 0,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let err = train_until_converged(m, &config, 3, &dev, &mut SilentObserver)
+        .err()
+        .expect("training should not converge");
+    assert!(matches!(err, UtfRnnError::NotConverged { attempts: 3, .. }));
+    assert!(err.to_string().contains("after 3 attempts"));
+
+    Ok(())
+}
+
+#[test]
+fn predict_handles_batches() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let m = sample_dataset(&dev)?;
+
+    let model =
+        train_until_converged(m.clone(), &config, MAX_ATTEMPTS, &dev, &mut SilentObserver)?.model;
+
+    // A converged model classifies the whole test set correctly.
+    let predictions = model.predict(&m.test_votes)?;
+    assert_eq!(predictions, m.test_results.to_vec1::<u32>()?);
+    assert_eq!(model.predict_one(&[13., 9.])?, predictions[0]);
+
+    Ok(())
+}
+
+#[test]
+fn predict_proba_matches_predict() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let m = sample_dataset(&dev)?;
+
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+
+    let proba = model.predict_proba(&m.test_votes)?;
+    assert_eq!(proba.dims2()?, (3, 2));
+    for row in proba.sum(D::Minus1)?.to_vec1::<f32>()? {
+        assert!((row - 1.0).abs() < 1e-5);
+    }
+    assert_eq!(
+        proba.argmax(D::Minus1)?.to_vec1::<u32>()?,
+        model.predict(&m.test_votes)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn save_round_trip() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let m = sample_dataset(&dev)?;
+    let trained =
+        train_until_converged(m.clone(), &config, MAX_ATTEMPTS, &dev, &mut SilentObserver)?;
+
+    let path = std::env::temp_dir().join("utf-rnn-save-round-trip.safetensors");
+    trained.model.save(&trained.varmap, &path)?;
+
+    // Build a fresh model and overwrite its random weights with the saved ones.
+    let mut varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    varmap.load(&path)?;
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(
+        model.predict_proba(&m.test_votes)?.to_vec2::<f32>()?,
+        trained
+            .model
+            .predict_proba(&m.test_votes)?
+            .to_vec2::<f32>()?
+    );
+    assert_eq!(
+        model.predict(&m.test_votes)?,
+        trained.model.predict(&m.test_votes)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn load_restores_saved_weights() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let m = sample_dataset(&dev)?;
+
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let path = std::env::temp_dir().join("utf-rnn-load.safetensors");
+    model.save(&varmap, &path)?;
+
+    let loaded = MultiLevelPerceptron::load(&path, &dev, &config)?;
+    assert_eq!(
+        loaded.predict_proba(&m.test_votes)?.to_vec2::<f32>()?,
+        model.predict_proba(&m.test_votes)?.to_vec2::<f32>()?
+    );
+
+    // Loading into a differently sized network is rejected with a clear error.
+    let wider = TrainConfig {
+        hidden_sizes: vec![5, 2],
+        ..config.clone()
+    };
+    let err = MultiLevelPerceptron::load(&path, &dev, &wider)
+        .err()
+        .expect("shapes should not match");
+    assert!(matches!(err, UtfRnnError::ShapeMismatch { .. }));
+    assert!(err.to_string().contains("ln1"));
+    std::fs::remove_file(&path)?;
+
+    Ok(())
+}
+
+#[test]
+fn device_spec_parsing() -> anyhow::Result<()> {
+    assert_eq!("auto".parse::<DeviceSpec>()?, DeviceSpec::Auto);
+    assert_eq!("CPU".parse::<DeviceSpec>()?, DeviceSpec::Cpu);
+    assert_eq!("cuda".parse::<DeviceSpec>()?, DeviceSpec::Cuda(0));
+    assert_eq!("cuda:1".parse::<DeviceSpec>()?, DeviceSpec::Cuda(1));
+    assert_eq!("metal:0".parse::<DeviceSpec>()?, DeviceSpec::Metal(0));
+    assert!("tpu".parse::<DeviceSpec>().is_err());
+    assert!("cpu:1".parse::<DeviceSpec>().is_err());
+    assert!("cuda:x".parse::<DeviceSpec>().is_err());
+
+    assert!(resolve_device(&DeviceSpec::Cpu)?.is_cpu());
+    // Without the GPU features every device resolves to the CPU or fails.
+    if !cfg!(feature = "cuda") {
+        assert!(matches!(
+            resolve_device(&DeviceSpec::Cuda(0)),
+            Err(UtfRnnError::Device(_))
+        ));
+    }
+    if !cfg!(any(feature = "cuda", feature = "metal")) {
+        assert!(resolve_device(&DeviceSpec::Auto)?.is_cpu());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn same_seed_gives_identical_weights() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        seed: Some(42),
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let weights = |config: &TrainConfig| -> anyhow::Result<Vec<(String, Vec<f32>)>> {
+        let varmap = fit(m.clone(), config, &dev, None)?.trained.varmap;
+        let vars = varmap.data().lock().unwrap();
+        let mut weights = vars
+            .iter()
+            .map(|(name, var)| Ok((name.clone(), var.flatten_all()?.to_vec1::<f32>()?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        weights.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(weights)
+    };
+
+    assert_eq!(weights(&config)?, weights(&config)?);
+    let other = TrainConfig {
+        seed: Some(7),
+        ..config.clone()
+    };
+    assert_ne!(weights(&config)?, weights(&other)?);
+
+    Ok(())
+}
+
+#[test]
+fn train_records_epoch_metrics() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 5,
+        seed: Some(1),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let outcome = fit(m, &config, &dev, None)?;
+    let metrics = &outcome.trained.metrics;
+    assert_eq!(metrics.len(), outcome.epochs);
+    assert!(!metrics.is_empty() && metrics.len() <= config.epochs);
+    for (i, epoch) in metrics.iter().enumerate() {
+        assert_eq!(epoch.epoch, i + 1);
+        assert!((0.0..=100.0).contains(&epoch.test_accuracy));
+    }
+    assert_eq!(metrics.last().unwrap().test_accuracy, outcome.accuracy);
+
+    Ok(())
+}
+
+#[test]
+fn epoch_callback_can_stop_training() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 50,
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let mut seen = Vec::new();
+    let mut on_epoch = |metrics: &EpochMetrics| {
+        seen.push(metrics.epoch);
+        if metrics.epoch == 2 {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    };
+    let outcome = fit(m, &config, &dev, Some(&mut on_epoch))?;
+
+    // Training stops at the requested epoch unless it converged even earlier.
+    assert!(outcome.epochs <= 2);
+    assert_eq!(seen, (1..=outcome.epochs).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+#[test]
+fn early_stopping_restores_best_weights() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 1000,
+        seed: Some(3),
+        verbose: false,
+        early_stopping: Some(EarlyStopping {
+            patience: 5,
+            min_delta: 0.0,
+        }),
+        ..Default::default()
+    };
+    // The same vote row labelled both ways can never be classified perfectly,
+    // so the test loss plateaus (or rises) as the model grows confident.
+    let m = unlearnable_dataset(&dev)?;
+
+    let outcome = fit(m.clone(), &config, &dev, None)?;
+    let metrics = &outcome.trained.metrics;
+    assert!(metrics.len() < config.epochs, "early stopping never fired");
+
+    // The returned weights are the ones from the epoch with the lowest test loss.
+    let best = metrics
+        .iter()
+        .min_by(|a, b| a.test_loss.total_cmp(&b.test_loss))
+        .unwrap();
+    let logits = outcome.trained.model.forward(&m.test_votes)?;
+    let test_loss =
+        loss::nll(&ops::log_softmax(&logits, D::Minus1)?, &m.test_results)?.to_scalar::<f32>()?;
+    assert!((test_loss - best.test_loss).abs() < 1e-6);
+    assert_eq!(outcome.accuracy, best.test_accuracy);
+
+    Ok(())
+}
+
+#[test]
+fn mini_batch_training_converges() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        batch_size: Some(4),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let trained =
+        train_until_converged(m.clone(), &config, MAX_ATTEMPTS, &dev, &mut SilentObserver)?;
+    assert_eq!(
+        trained.model.predict(&m.test_votes)?,
+        m.test_results.to_vec1::<u32>()?
+    );
+    assert!(trained.metrics.iter().all(|e| e.train_loss.is_finite()));
+
+    Ok(())
+}
+
+#[test]
+fn shuffling_keeps_votes_and_labels_aligned() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let m = sample_dataset(&dev)?;
+
+    let pairs = |votes: &Tensor, results: &Tensor| -> anyhow::Result<Vec<(Vec<u32>, u32)>> {
+        let votes = votes.to_dtype(DType::U32)?.to_vec2::<u32>()?;
+        let mut pairs: Vec<_> = votes.into_iter().zip(results.to_vec1::<u32>()?).collect();
+        pairs.sort();
+        Ok(pairs)
+    };
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let (votes, results) = shuffle_rows(&m.train_votes, &m.train_results, &mut rng)?;
+    assert_eq!(votes.dims(), m.train_votes.dims());
+    assert_eq!(
+        pairs(&votes, &results)?,
+        pairs(&m.train_votes, &m.train_results)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn deeper_networks_train() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        hidden_sizes: vec![8, 8, 4, 4],
+        seed: Some(5),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let outcome = fit(m.clone(), &config, &dev, None)?;
+    let model = &outcome.trained.model;
+    assert_eq!(model.layers.len(), 5);
+    assert_eq!(model.predict_proba(&m.test_votes)?.dims2()?, (3, 2));
+    assert_eq!(outcome.trained.varmap.all_vars().len(), 10);
+    assert!(outcome
+        .trained
+        .metrics
+        .iter()
+        .all(|e| e.train_loss.is_finite()));
+
+    Ok(())
+}
+
+#[test]
+fn activations_train_end_to_end() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+
+    let xs = Tensor::new(&[0f32], &dev)?;
+    assert_eq!(
+        apply_activation(&xs, Activation::Tanh)?.to_vec1::<f32>()?,
+        [0.]
+    );
+    assert_eq!(
+        apply_activation(&xs, Activation::Sigmoid)?.to_vec1::<f32>()?,
+        [0.5]
+    );
+
+    for activation in [Activation::Relu, Activation::Gelu] {
+        let config = TrainConfig {
+            activation,
+            verbose: false,
+            ..Default::default()
+        };
+        let m = sample_dataset(&dev)?;
+
+        let trained =
+            train_until_converged(m.clone(), &config, MAX_ATTEMPTS, &dev, &mut SilentObserver)?;
+        assert_eq!(trained.model.activation, activation);
+        assert_eq!(
+            trained.model.predict(&m.test_votes)?,
+            m.test_results.to_vec1::<u32>()?
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn dropout_only_applies_in_training_mode() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        hidden_sizes: vec![64],
+        dropout: 0.5,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
 
-    let test_results_vec: Vec<u32> = vec![1, 0, 0];
-    let test_results_tensor =
-        Tensor::from_vec(test_results_vec.clone(), test_results_vec.len(), &dev)?;
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
 
+    let eval = |train| -> anyhow::Result<Vec<Vec<f32>>> {
+        Ok(model.forward_t(&m.train_votes, train)?.to_vec2::<f32>()?)
+    };
+    assert_eq!(eval(false)?, eval(false)?);
+    assert_ne!(eval(true)?, eval(true)?);
+
+    Ok(())
+}
+
+#[test]
+fn char_rnn_scores_every_position() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let (vocab_size, batch, seq_len) = (16, 3, 5);
+
+    let varmap = VarMap::new();
+    let rnn = CharRnn::new(
+        vocab_size,
+        8,
+        12,
+        VarBuilder::from_varmap(&varmap, DType::F32, &dev),
+    )?;
+
+    let indices: Vec<u32> = (0..(batch * seq_len) as u32)
+        .map(|i| i % vocab_size as u32)
+        .collect();
+    let seq = Tensor::from_vec(indices, (batch, seq_len), &dev)?;
+    let logits = rnn.forward(&seq)?;
+    assert_eq!(logits.dims(), &[batch, seq_len, vocab_size]);
+
+    Ok(())
+}
+
+#[test]
+fn char_vocab_round_trips_unicode() {
+    let text = "héllo 世界 👋🏽 ok";
+    let vocab = CharVocab::new(text);
+
+    let ids = vocab.encode(text);
+    assert_eq!(ids.len(), text.chars().count());
+    assert!(ids.iter().all(|&id| (id as usize) < vocab.len()));
+    assert_eq!(vocab.decode(&ids), text);
+}
+
+#[test]
+fn text_file_windows_are_shifted_by_one() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let path = std::env::temp_dir().join("utf-rnn-text-file-windows.txt");
+    std::fs::write(&path, "abcabcabcab")?;
+
+    let (m, vocab) = Dataset::from_text_file_with_ratio(&path, 4, 0.5, &dev)?;
+    assert_eq!(vocab.len(), 3);
+    // 11 characters give 7 windows of length 4
+    assert_eq!(m.train_votes.dims(), &[4, 4]);
+    assert_eq!(m.test_votes.dims(), &[3, 4]);
+    assert_eq!(m.test_results.dims(), &[3, 4]);
+
+    let votes = m.train_votes.to_vec2::<u32>()?;
+    let results = m.train_results.to_vec2::<u32>()?;
+    assert_eq!(vocab.decode(&votes[1]), "bcab");
+    assert_eq!(vocab.decode(&results[1]), "cabc");
+
+    // A ratio rounding up to every window still keeps one for testing.
+    let (m, _) = Dataset::from_text_file_with_ratio(&path, 4, 0.95, &dev)?;
+    assert_eq!(m.train_votes.dims(), &[6, 4]);
+    assert_eq!(m.test_votes.dims(), &[1, 4]);
+    let (m, _) = Dataset::from_text_file_with_ratio(&path, 4, 1.0, &dev)?;
+    assert_eq!(m.train_votes.dims(), &[7, 4]);
+    assert_eq!(m.test_votes.dim(0)?, 0);
+
+    std::fs::write(&path, "")?;
+    assert!(Dataset::from_text_file(&path, 4, &dev).is_err());
+    std::fs::write(&path, "abcd")?;
+    let err = Dataset::from_text_file(&path, 4, &dev).unwrap_err();
+    assert!(err.to_string().contains("seq_len 4"));
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn csv_fixture_has_expected_shapes() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("data/votes.csv");
+
+    let m = Dataset::from_csv(&path, &[0, 1], 2, &dev)?;
+    // 11 rows, 80% of them rounded up into the training set
+    assert_eq!(m.train_votes.dims(), &[9, 2]);
+    assert_eq!(m.train_results.dims(), &[9]);
+    assert_eq!(m.test_votes.dims(), &[2, 2]);
+    assert_eq!(m.test_results.dims(), &[2]);
+    assert_eq!(m.train_votes.dtype(), DType::F32);
+    assert_eq!(m.train_results.to_vec1::<u32>()?[..3], [1, 0, 0]);
+
+    let m = Dataset::from_csv_with_ratio(&path, &[1], 2, 0.5, &dev)?;
+    assert_eq!(m.train_votes.dims(), &[6, 1]);
+    assert_eq!(m.test_votes.dims(), &[5, 1]);
+    // A ratio rounding up to every row still keeps one for testing.
+    let m = Dataset::from_csv_with_ratio(&path, &[1], 2, 0.99, &dev)?;
+    assert_eq!(m.train_votes.dims(), &[10, 1]);
+    assert_eq!(m.test_votes.dims(), &[1, 1]);
+    assert!(matches!(
+        Dataset::from_csv_with_ratio(&path, &[1], 2, 1.0, &dev),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+
+    assert!(Dataset::from_csv(&path, &[0, 3], 2, &dev).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn split_partitions_rows_disjointly() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let rows = 20;
+    // Every row holds its own index, so the partitions can be traced back.
+    let votes = Tensor::arange(0f32, rows as f32, &dev)?.reshape((rows, 1))?;
+    let labels = Tensor::arange(0u32, rows as u32, &dev)?;
+
+    let split = Dataset::split(votes.clone(), labels.clone(), (0.6, 0.2, 0.2), 7)?;
+    assert_eq!(split.train_votes.dims(), &[12, 1]);
+    assert_eq!(split.val_votes.dims(), &[4, 1]);
+    assert_eq!(split.test_votes.dims(), &[4, 1]);
+
+    let mut seen: Vec<u32> = Vec::new();
+    for (part_votes, part_labels) in [
+        (&split.train_votes, &split.train_results),
+        (&split.val_votes, &split.val_results),
+        (&split.test_votes, &split.test_results),
+    ] {
+        let ids = part_labels.to_vec1::<u32>()?;
+        let part_votes: Vec<u32> = part_votes
+            .flatten_all()?
+            .to_vec1::<f32>()?
+            .into_iter()
+            .map(|v| v as u32)
+            .collect();
+        assert_eq!(part_votes, ids, "votes and labels went to different rows");
+        seen.extend(ids);
+    }
+    seen.sort_unstable();
+    assert_eq!(seen, (0..rows as u32).collect::<Vec<_>>());
+
+    let again = Dataset::split(votes.clone(), labels.clone(), (0.6, 0.2, 0.2), 7)?;
+    assert_eq!(
+        again.val_results.to_vec1::<u32>()?,
+        split.val_results.to_vec1::<u32>()?
+    );
+    assert!(Dataset::from(split).validation.is_some());
+    assert!(Dataset::split(votes, labels, (0.6, 0.6, 0.2), 7).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn early_stopping_watches_the_validation_set() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 1000,
+        seed: Some(3),
+        verbose: false,
+        early_stopping: Some(EarlyStopping {
+            patience: 5,
+            min_delta: 0.0,
+        }),
+        ..Default::default()
+    };
+    // Contradictory labels keep both held-out losses from reaching zero, with
+    // different rows so the two losses bottom out at different epochs.
+    let mut m = unlearnable_dataset(&dev)?;
+    let val_votes = Tensor::new(&[[8f32, 14.], [8., 14.]], &dev)?;
+    let val_results = Tensor::new(&[0u32, 1], &dev)?;
+    m.validation = Some((val_votes.clone(), val_results.clone()));
+
+    let outcome = fit(m, &config, &dev, None)?;
+    let metrics = &outcome.trained.metrics;
+    assert!(metrics.len() < config.epochs, "early stopping never fired");
+
+    // The returned weights are the ones from the epoch with the lowest validation loss.
+    let best = metrics
+        .iter()
+        .min_by(|a, b| a.val_loss.unwrap().total_cmp(&b.val_loss.unwrap()))
+        .unwrap();
+    let logits = outcome.trained.model.forward(&val_votes)?;
+    let val_loss =
+        loss::nll(&ops::log_softmax(&logits, D::Minus1)?, &val_results)?.to_scalar::<f32>()?;
+    assert!((val_loss - best.val_loss.unwrap()).abs() < 1e-6);
+    assert_eq!(outcome.accuracy, best.test_accuracy);
+
+    Ok(())
+}
+
+#[test]
+fn trains_on_three_classes() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        num_classes: 3,
+        hidden_sizes: vec![8],
+        optimizer: Optimizer::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.0,
+        },
+        epochs: 200,
+        seed: Some(5),
+        verbose: false,
+        ..Default::default()
+    };
+    // Three well separated clusters, one per class.
+    let votes = Tensor::new(
+        &[
+            [1f32, 1.],
+            [2., 1.],
+            [1., 2.],
+            [10., 1.],
+            [11., 2.],
+            [10., 2.],
+            [1., 10.],
+            [2., 11.],
+            [1., 11.],
+        ],
+        &dev,
+    )?;
+    let results = Tensor::new(&[0u32, 0, 0, 1, 1, 1, 2, 2, 2], &dev)?;
     let m = Dataset {
-        train_votes: train_votes_tensor,
-        train_results: train_results_tensor,
-        test_votes: test_votes_tensor,
-        test_results: test_results_tensor,
-    };
-
-    let trained_model: MultiLevelPerceptron;
-    loop {
-        println!("Trying to train neural network.");
-        match train(m.clone(), &dev) {
-            Ok(model) => {
-                trained_model = model;
-                break;
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-                continue;
+        train_votes: votes.clone(),
+        train_results: results.clone(),
+        test_votes: votes.clone(),
+        test_results: results,
+        validation: None,
+    };
+
+    let outcome = fit(m, &config, &dev, None)?;
+    let model = &outcome.trained.model;
+    assert_eq!(model.forward(&votes)?.dims(), &[9, 3]);
+    assert!(model.predict(&votes)?.iter().all(|&class| class < 3));
+
+    let metrics = &outcome.trained.metrics;
+    assert!(metrics.iter().all(|e| e.train_loss.is_finite()));
+    assert!(metrics.last().unwrap().train_loss <= metrics[0].train_loss);
+
+    Ok(())
+}
+
+#[test]
+fn confusion_matrix_counts_every_test_sample() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let trained =
+        train_until_converged(m.clone(), &config, MAX_ATTEMPTS, &dev, &mut SilentObserver)?;
+
+    let matrix = evaluate(&trained.model, &m.test_votes, &m.test_results)?;
+    assert_eq!(matrix.n_classes(), 2);
+    assert_eq!(matrix.total(), m.test_results.dims1()?);
+    // A converged model classifies the whole test set correctly.
+    for class in 0..2 {
+        assert_eq!(matrix.precision(class), 1.0);
+        assert_eq!(matrix.recall(class), 1.0);
+        assert_eq!(matrix.f1(class), 1.0);
+    }
+
+    let mut matrix = ConfusionMatrix::new(2);
+    matrix.record(0, 0);
+    matrix.record(0, 1);
+    matrix.record(1, 1);
+    assert_eq!(matrix.precision(1), 0.5);
+    assert_eq!(matrix.recall(0), 0.5);
+    assert_eq!(matrix.recall(1), 1.0);
+    assert!((matrix.f1(1) - 2.0 / 3.0).abs() < 1e-6);
+
+    Ok(())
+}
+
+#[test]
+fn standardized_votes_have_zero_mean_and_unit_variance() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        normalize: true,
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let standardizer = Standardizer::fit(&m.train_votes)?;
+    let normalized = standardizer.transform(&m.train_votes)?;
+    let mean = normalized.mean(0)?.to_vec1::<f32>()?;
+    let var = normalized.sqr()?.mean(0)?.to_vec1::<f32>()?;
+    for feature in 0..config.vote_dim {
+        assert!(mean[feature].abs() < 1e-5, "mean {}", mean[feature]);
+        assert!(
+            (var[feature] - 1.0).abs() < 1e-4,
+            "variance {}",
+            var[feature]
+        );
+    }
+
+    // The trained model keeps the statistics and applies them to raw votes.
+    let trained =
+        train_until_converged(m.clone(), &config, MAX_ATTEMPTS, &dev, &mut SilentObserver)?;
+    let kept = trained.model.standardizer.as_ref().unwrap();
+    assert_eq!(
+        kept.mean.to_vec2::<f32>()?,
+        standardizer.mean.to_vec2::<f32>()?
+    );
+    assert_eq!(
+        trained.model.predict(&m.test_votes)?,
+        m.test_results.to_vec1::<u32>()?
+    );
+
+    // So does a model reloaded from disk.
+    let path = std::env::temp_dir().join("utf-rnn-standardized.safetensors");
+    trained.model.save(&trained.varmap, &path)?;
+    let loaded = MultiLevelPerceptron::load(&path, &dev, &config)?;
+    std::fs::remove_file(&path)?;
+    let restored = loaded.standardizer.as_ref().unwrap();
+    assert_eq!(restored.std.to_vec2::<f32>()?, kept.std.to_vec2::<f32>()?);
+    assert_eq!(
+        loaded.predict(&m.test_votes)?,
+        trained.model.predict(&m.test_votes)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn grad_clipping_keeps_high_learning_rate_finite() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        learning_rate: 10.0,
+        grad_clip: Some(1.0),
+        epochs: 50,
+        seed: Some(1),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let outcome = fit(m, &config, &dev, None)?;
+    for e in &outcome.trained.metrics {
+        assert!(
+            e.train_loss.is_finite(),
+            "epoch {}: {}",
+            e.epoch,
+            e.train_loss
+        );
+        assert!(
+            e.test_loss.is_finite(),
+            "epoch {}: {}",
+            e.epoch,
+            e.test_loss
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn diverging_training_reports_non_finite_loss() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        learning_rate: 1e39,
+        seed: Some(1),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    // Every attempt diverges, so the retry loop hands back the last divergence.
+    let mut observer = RecordingObserver::default();
+    let err = train_until_converged(m, &config, MAX_ATTEMPTS, &dev, &mut observer)
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err, UtfRnnError::Train(TrainError::NonFiniteLoss { .. })),
+        "unexpected error: {err}"
+    );
+    assert_eq!(observer.attempts.len(), MAX_ATTEMPTS);
+
+    Ok(())
+}
+
+#[test]
+fn resumed_training_matches_uninterrupted_run() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let dir = std::env::temp_dir().join("utf-rnn-resume-checkpoints");
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = TrainConfig {
+        epochs: 6,
+        seed: Some(11),
+        verbose: false,
+        ..Default::default()
+    };
+    // An unlearnable test set keeps either run from stopping at 100% accuracy.
+    let m = unlearnable_dataset(&dev)?;
+
+    let uninterrupted = fit(m.clone(), &config, &dev, None)?.trained;
+
+    let first_half = TrainConfig {
+        epochs: 3,
+        checkpoint_every: Some(3),
+        checkpoint_dir: dir.clone(),
+        ..config.clone()
+    };
+    fit(m.clone(), &first_half, &dev, None)?;
+    assert!(dir.join("epoch-3.safetensors").exists());
+    assert!(dir.join("epoch-3.json").exists());
+
+    let resumed = resume_training(&dir, m, &config, &dev)?;
+    assert_eq!(
+        resumed.metrics.iter().map(|e| e.epoch).collect::<Vec<_>>(),
+        [4, 5, 6]
+    );
+    assert_eq!(resumed.metrics, uninterrupted.metrics[3..]);
+    for (name, var) in uninterrupted.varmap.data().lock().unwrap().iter() {
+        let resumed_var = &resumed.varmap.data().lock().unwrap()[name];
+        assert_eq!(
+            var.flatten_all()?.to_vec1::<f32>()?,
+            resumed_var.flatten_all()?.to_vec1::<f32>()?,
+            "{name} differs"
+        );
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn cosine_schedule_reaches_min_lr_at_the_last_epoch() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 8,
+        learning_rate: 0.05,
+        lr_schedule: LrSchedule::Cosine { min_lr: 0.001 },
+        seed: Some(2),
+        verbose: false,
+        ..Default::default()
+    };
+    // An unlearnable test set keeps training from stopping before the last epoch.
+    let m = unlearnable_dataset(&dev)?;
+
+    let metrics = fit(m, &config, &dev, None)?.trained.metrics;
+    assert_eq!(metrics.len(), config.epochs);
+    assert!((metrics[0].learning_rate - 0.05).abs() < 1e-12);
+    assert!((metrics.last().unwrap().learning_rate - 0.001).abs() < 1e-12);
+    assert!(metrics
+        .windows(2)
+        .all(|pair| pair[1].learning_rate < pair[0].learning_rate));
+
+    let step = LrSchedule::StepDecay {
+        step: 2,
+        gamma: 0.5,
+    };
+    let rates: Vec<f64> = (1..=5)
+        .map(|epoch| step.learning_rate(0.8, epoch, 5))
+        .collect();
+    assert_eq!(rates, [0.8, 0.8, 0.4, 0.4, 0.2]);
+
+    Ok(())
+}
+
+#[test]
+fn weight_decay_shrinks_the_weights() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 50,
+        seed: Some(4),
+        verbose: false,
+        ..Default::default()
+    };
+    // An unlearnable test set keeps both runs going for every epoch.
+    let m = unlearnable_dataset(&dev)?;
+
+    let weight_norm = |config: &TrainConfig| -> anyhow::Result<f32> {
+        let trained = fit(m.clone(), config, &dev, None)?.trained;
+        let vars = trained.varmap.data().lock().unwrap();
+        let mut sum_sq = 0.0;
+        for (name, var) in vars.iter() {
+            if name.ends_with(".weight") {
+                sum_sq += var.sqr()?.sum_all()?.to_scalar::<f32>()?;
             }
         }
-    }
+        Ok(sum_sq.sqrt())
+    };
+    let plain = weight_norm(&config)?;
+    let decayed = weight_norm(&TrainConfig {
+        weight_decay: 0.5,
+        ..config.clone()
+    })?;
+    assert!(decayed < plain, "decayed {decayed} >= plain {plain}");
 
-    let real_world_votes: Vec<u32> = vec![13, 22];
+    Ok(())
+}
 
-    let tensor_test_votes =
-        Tensor::from_vec(real_world_votes.clone(), (1, VOTE_DIM), &dev)?.to_dtype(DType::F32)?;
+#[test]
+fn weight_decay_skips_the_embedding_and_frozen_layers() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        input_mode: InputMode::Categorical {
+            vocab_size: 5,
+            embed_dim: 3,
+        },
+        frozen: vec!["ln1".to_string()],
+        weight_decay: 0.5,
+        verbose: false,
+        ..Default::default()
+    };
+    let varmap = VarMap::new();
+    MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
 
-    let final_result = trained_model.forward(&tensor_test_votes)?;
+    let mut names: Vec<String> = decayed_vars(&varmap, &config)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["ln2.weight", "ln3.weight"]);
 
-    let result = final_result
-        .argmax(D::Minus1)?
-        .to_dtype(DType::F32)?
-        .get(0)
-        .map(|x| x.to_scalar::<f32>())??;
-    println!("real_life_votes: {:?}", real_world_votes);
-    println!("neural_network_prediction_result: {:?}", result);
+    // Training with the decay leaves the frozen layer as it was.
+    let ids = Tensor::new(&[[0u32, 4], [2, 2], [3, 1], [1, 0]], &dev)?;
+    let results = Tensor::new(&[0u32, 1, 1, 0], &dev)?;
+    let m = Dataset {
+        train_votes: ids.clone(),
+        train_results: results.clone(),
+        test_votes: ids,
+        test_results: results,
+        validation: None,
+    };
+    let config = TrainConfig {
+        epochs: 20,
+        seed: Some(2),
+        stop_on_accuracy: false,
+        ..config
+    };
+    let initial = {
+        let varmap = VarMap::new();
+        let model = PerceptronBuilder::from_config(&config)
+            .input_dim(VOTE_DIM)
+            .build(VarBuilder::from_varmap(&varmap, DType::F32, &dev))?;
+        init_seeded(&varmap, 2, config.init)?;
+        model.layers[0].weight().to_vec2::<f32>()?
+    };
+    let trained = fit(m, &config, &dev, None)?.trained;
+    assert_eq!(trained.model.layers[0].weight().to_vec2::<f32>()?, initial);
+
+    Ok(())
+}
+
+#[test]
+fn predict_batch_matches_single_predictions() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let rows: Vec<[f32; VOTE_DIM]> = (0..256)
+        .map(|i| [(i % 32) as f32, (i / 8) as f32])
+        .collect();
+
+    let batched = model.predict_batch(&rows)?;
+    let single: Vec<u32> = rows
+        .iter()
+        .map(|row| model.predict_one(row))
+        .collect::<Result<_, _>>()?;
+
+    assert_eq!(batched.len(), rows.len());
+    assert_eq!(
+        batched.iter().map(|(class, _)| *class).collect::<Vec<_>>(),
+        single
+    );
+    assert!(batched
+        .iter()
+        .all(|(_, confidence)| (0.5..=1.0).contains(confidence)));
+
+    Ok(())
+}
+
+/// Wall-clock comparison, so only run with the benchmarks.
+#[cfg(feature = "bench")]
+#[test]
+fn predict_batch_is_faster_than_single_predictions() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let rows: Vec<[f32; VOTE_DIM]> = (0..256)
+        .map(|i| [(i % 32) as f32, (i / 8) as f32])
+        .collect();
+
+    let start = std::time::Instant::now();
+    model.predict_batch(&rows)?;
+    let batched_time = start.elapsed();
+
+    let start = std::time::Instant::now();
+    for row in &rows {
+        model.predict_one(row)?;
+    }
+    let single_time = start.elapsed();
+
+    assert!(
+        batched_time * 2 < single_time,
+        "batched: {batched_time:?}, single calls: {single_time:?}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn predictor_round_trips_model_and_preprocessing() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        normalize: true,
+        seed: Some(6),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let trained = fit(m, &config, &dev, None)?.trained;
+    let predictor = Predictor::new(trained, &config, Some(CharVocab::new("yes no")))?;
+
+    let dir = std::env::temp_dir().join("utf-rnn-predictor-round-trip");
+    predictor.save(&dir)?;
+    let loaded = Predictor::load(&dir, &dev)?;
+
+    for votes in [[13f32, 22.], [30., 20.], [3., 10.]] {
+        assert_eq!(loaded.predict(&votes)?, predictor.predict(&votes)?);
+    }
+    let standardizer = loaded.model().standardizer.as_ref().unwrap();
+    assert_eq!(standardizer.mean.dims(), &[1, config.vote_dim]);
+    assert_eq!(
+        loaded.vocab().unwrap().encode("no yes"),
+        predictor.vocab().unwrap().encode("no yes")
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn predict_topk_ranks_the_most_likely_classes() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        num_classes: 3,
+        hidden_sizes: vec![8],
+        ..Default::default()
+    };
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let votes = Tensor::new(&[[1f32, 1.], [10., 2.], [2., 11.]], &dev)?;
+
+    let top = model.predict_topk(&votes, 2)?;
+    let best = model.predict(&votes)?;
+    let probabilities = model.predict_proba(&votes)?.to_vec2::<f32>()?;
+    assert_eq!(top.len(), 3);
+    for ((row, class), probs) in top.iter().zip(best).zip(probabilities) {
+        assert_eq!(row.len(), 2);
+        assert_eq!(row[0].0, class);
+        assert!(row[0].1 >= row[1].1);
+        assert_ne!(row[0].0, row[1].0);
+        // The class left out is the least likely one.
+        let left_out = (0..3)
+            .find(|c| row.iter().all(|(class, _)| class != c))
+            .unwrap();
+        assert!(probs[left_out as usize] <= row[1].1);
+    }
+    assert_eq!(model.predict_topk(&votes, 5)?[0].len(), 3);
+
+    // Logits, probabilities and classes all agree.
+    let logits = model.logits(&votes)?;
+    assert_eq!(logits.dims(), &[3, 3]);
+    assert_eq!(
+        ops::softmax(&logits, D::Minus1)?.to_vec2::<f32>()?,
+        model.predict_proba(&votes)?.to_vec2::<f32>()?
+    );
+    assert_eq!(
+        logits.argmax(D::Minus1)?.to_vec1::<u32>()?,
+        model.predict(&votes)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn cross_entropy_matches_log_softmax_nll() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let logits = model.forward(&m.train_votes)?;
+
+    let cross_entropy = Loss::CrossEntropy
+        .compute(&logits, &m.train_results)?
+        .to_scalar::<f32>()?;
+    let nll = Loss::LogSoftmaxNll
+        .compute(&logits, &m.train_results)?
+        .to_scalar::<f32>()?;
+    assert!((cross_entropy - nll).abs() < 1e-5);
+
+    Ok(())
+}
+
+#[test]
+fn class_weights_improve_minority_recall() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    // Class 1 only appears in the second cluster, where it is outnumbered
+    // three to one, so an unweighted model never predicts it.
+    let mut rows = vec![[1f32, 1.]; 18];
+    rows.extend([[5f32, 5.]; 8]);
+    let mut labels = vec![0u32; 24];
+    labels.extend([1, 1]);
+    let votes = Tensor::from_slice(rows.as_flattened(), (rows.len(), 2), &dev)?;
+    let results = Tensor::new(labels.as_slice(), &dev)?;
+    let m = Dataset {
+        train_votes: votes.clone(),
+        train_results: results.clone(),
+        test_votes: votes.clone(),
+        test_results: results.clone(),
+        validation: None,
+    };
+
+    let weights = inverse_frequency_weights(&results, 2)?;
+    assert!(weights[1] > weights[0]);
+
+    let minority_recall = |class_weights: Option<Vec<f32>>| -> anyhow::Result<f32> {
+        let config = TrainConfig {
+            hidden_sizes: vec![8],
+            optimizer: Optimizer::AdamW {
+                beta1: 0.9,
+                beta2: 0.999,
+                weight_decay: 0.0,
+            },
+            epochs: 300,
+            normalize: true,
+            class_weights,
+            seed: Some(9),
+            verbose: false,
+            ..Default::default()
+        };
+        let trained = fit(m.clone(), &config, &dev, None)?.trained;
+        Ok(evaluate(&trained.model, &votes, &results)?.recall(1))
+    };
+    let unweighted = minority_recall(None)?;
+    let weighted = minority_recall(Some(weights))?;
+    assert!(weighted > unweighted, "{weighted} <= {unweighted}");
+
+    Ok(())
+}
+
+#[test]
+fn trains_in_bf16() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        dtype: DType::BF16,
+        normalize: true,
+        epochs: 20,
+        seed: Some(2),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let outcome = fit(m.clone(), &config, &dev, None)?;
+
+    let model = &outcome.trained.model;
+    assert_eq!(model.layers[0].weight().dtype(), DType::BF16);
+    let logits = model.forward(&m.test_votes)?;
+    assert_eq!(logits.dtype(), DType::F32);
+    assert_eq!(
+        logits.dims(),
+        &[m.test_results.dims1()?, config.num_classes]
+    );
+    assert!(outcome
+        .trained
+        .metrics
+        .iter()
+        .all(|e| e.train_loss.is_finite() && e.test_loss.is_finite()));
+
+    Ok(())
+}
+
+#[test]
+fn categorical_inputs_are_embedded() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        input_mode: InputMode::Categorical {
+            vocab_size: 5,
+            embed_dim: 3,
+        },
+        num_classes: 3,
+        verbose: false,
+        ..Default::default()
+    };
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    assert_eq!(
+        model.layers[0].weight().dims(),
+        &[config.hidden_sizes[0], VOTE_DIM * 3]
+    );
+
+    let ids = Tensor::new(&[[0u32, 4], [2, 2], [3, 1], [1, 0]], &dev)?;
+    assert_eq!(model.forward(&ids)?.dims(), &[4, 3]);
+
+    // Training casts the ids and learns the embedding too.
+    let results = Tensor::new(&[0u32, 1, 2, 0], &dev)?;
+    let m = Dataset {
+        train_votes: ids.clone(),
+        train_results: results.clone(),
+        test_votes: ids.clone(),
+        test_results: results,
+        validation: None,
+    };
+    let outcome = fit(m, &config, &dev, None)?;
+    assert_eq!(outcome.trained.model.forward(&ids)?.dims(), &[4, 3]);
+
+    Ok(())
+}
+
+#[test]
+fn builder_sets_layer_sizes_and_rejects_empty_layers() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let varmap = VarMap::new();
+    let vs = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let model = PerceptronBuilder::new()
+        .input_dim(3)
+        .hidden(vec![6, 5])
+        .num_classes(4)
+        .activation(Activation::Tanh)
+        .dropout(0.1)
+        .build(vs.clone())?;
+    let dims: Vec<&[usize]> = model.layers.iter().map(|l| l.weight().dims()).collect();
+    assert_eq!(dims, [&[6, 3][..], &[5, 6], &[4, 5]]);
+    assert_eq!(
+        model
+            .forward(&Tensor::zeros((2, 3), DType::F32, &dev)?)?
+            .dims(),
+        &[2, 4]
+    );
+
+    let empty = PerceptronBuilder::new()
+        .hidden(vec![4, 0])
+        .build(vs.pp("other"));
+    assert!(matches!(empty, Err(UtfRnnError::InvalidInput(_))));
+
+    Ok(())
+}
+
+#[test]
+fn mismatched_shapes_report_friendly_errors() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+
+    let wide = Tensor::zeros((4, 3), DType::F32, &dev)?;
+    match model.forward(&wide) {
+        Err(UtfRnnError::ShapeMismatch { expected, got, .. }) => {
+            assert_eq!(expected, [4, 2]);
+            assert_eq!(got, [4, 3]);
+        }
+        other => panic!(
+            "expected a shape mismatch, got {:?}",
+            other.map(|t| t.shape().clone())
+        ),
+    }
+
+    // One label too few for the training votes.
+    let mut m = sample_dataset(&dev)?;
+    m.train_results = m.train_results.narrow(0, 1, m.train_results.dims1()? - 1)?;
+    match fit(m, &config, &dev, None) {
+        Err(UtfRnnError::ShapeMismatch {
+            name,
+            expected,
+            got,
+        }) => {
+            assert_eq!(name, "train results");
+            assert_eq!(expected, [8]);
+            assert_eq!(got, [7]);
+        }
+        _ => panic!("expected a shape mismatch"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn char_rnn_generates_the_requested_length() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let vocab = CharVocab::new("héllo wörld");
+    let varmap = VarMap::new();
+    let vs = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let rnn = CharRnn::new(vocab.len(), 4, 8, vs)?;
+
+    for temperature in [0.0, 0.8] {
+        let text = rnn.generate(&vocab, "hé", 12, temperature, None)?;
+        assert!(text.starts_with("hé"));
+        assert_eq!(text.chars().count(), 2 + 12);
+        assert!(text.chars().all(|c| vocab.id(c).is_some()));
+        // Every generated character decodes back to itself.
+        assert_eq!(vocab.decode(&vocab.encode(&text)), text);
+    }
+    // Greedy decoding is deterministic.
+    assert_eq!(
+        rnn.generate(&vocab, "w", 6, 0.0, None)?,
+        rnn.generate(&vocab, "w", 6, 0.0, None)?
+    );
+    assert!(rnn.generate(&vocab, "xyz", 6, 1.0, None).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn tiny_top_p_makes_generation_deterministic() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let vocab = CharVocab::new("abcdefgh");
+    let varmap = VarMap::new();
+    let vs = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let rnn = CharRnn::new(vocab.len(), 4, 8, vs)?;
+
+    // Only the most likely character survives, so sampling matches greedy decoding.
+    let greedy = rnn.generate(&vocab, "abc", 20, 0.0, None)?;
+    for _ in 0..3 {
+        assert_eq!(rnn.generate(&vocab, "abc", 20, 1.5, Some(1e-6))?, greedy);
+    }
+    assert_eq!(
+        rnn.generate(&vocab, "abc", 20, 1.0, Some(0.9))?
+            .chars()
+            .count(),
+        23
+    );
+
+    Ok(())
+}
+
+#[test]
+fn training_lowers_char_rnn_perplexity() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let path = std::env::temp_dir().join("utf-rnn-perplexity.txt");
+    std::fs::write(&path, "abcdabcdabcdabcdabcdabcdabcdabcdabcdabcd")?;
+    let (m, vocab) = Dataset::from_text_file(&path, 6, &dev)?;
+    std::fs::remove_file(&path)?;
+
+    let varmap = VarMap::new();
+    let vs = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let rnn = CharRnn::new(vocab.len(), 8, 16, vs)?;
+
+    // An untrained model is about as unsure as a uniform guess.
+    let sequences = Tensor::cat(&[&m.test_votes, &m.test_results.narrow(1, 5, 1)?], 1)?;
+    let untrained = perplexity(&rnn, &sequences, &dev)?;
+    let uniform = vocab.len() as f32;
+    assert!(
+        untrained > 0.5 * uniform && untrained < 1.5 * uniform,
+        "{untrained}"
+    );
+
+    let perplexities = char_rnn::train(&rnn, &varmap, &m, 30, 0.02, &dev)?;
+    assert_eq!(perplexities.len(), 30);
+    assert!(perplexities.last().unwrap() < &untrained);
+
+    Ok(())
+}
+
+#[test]
+fn fine_tuning_keeps_pretrained_accuracy() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let pretrained =
+        train_until_converged(m.clone(), &config, MAX_ATTEMPTS, &dev, &mut SilentObserver)?;
+    let path = std::env::temp_dir().join("utf-rnn-fine-tune.safetensors");
+    pretrained.model.save(&pretrained.varmap, &path)?;
+
+    // Rebuild the variables and load the saved weights into them.
+    let mut varmap = VarMap::new();
+    MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    varmap.load(&path)?;
+    std::fs::remove_file(&path)?;
+    let loaded = varmap.all_vars().len();
+
+    let fine_tune = TrainConfig {
+        epochs: 3,
+        learning_rate: 0.01,
+        seed: Some(1),
+        ..config.clone()
+    };
+    let outcome = train_with_varmap(varmap, m.clone(), &fine_tune, &dev)?;
+    assert_eq!(outcome.trained.varmap.all_vars().len(), loaded);
+    // The pretrained model classifies the whole test set correctly, and
+    // fine-tuning keeps it that way.
+    assert_eq!(outcome.accuracy, 100.0);
+
+    Ok(())
+}
+
+#[test]
+fn accept_best_returns_the_most_accurate_attempt() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        accept_best: true,
+        seed: Some(20),
+        verbose: false,
+        ..Default::default()
+    };
+    // Contradicting labels for the same votes keep every attempt below 100%.
+    let m = unlearnable_dataset(&dev)?;
+
+    let accuracy = |model: &MultiLevelPerceptron| -> anyhow::Result<f32> {
+        let matrix = evaluate(model, &m.test_votes, &m.test_results)?;
+        let correct: usize = (0..2).map(|class| matrix.count(class, class)).sum();
+        Ok(100. * correct as f32 / matrix.total() as f32)
+    };
+    // Replay the attempts with their per-attempt seeds.
+    let mut best = 0.0f32;
+    for offset in 0..3 {
+        let attempt = TrainConfig {
+            seed: Some(20 + offset),
+            ..config.clone()
+        };
+        best = best.max(accuracy(
+            &fit(m.clone(), &attempt, &dev, None)?.trained.model,
+        )?);
+    }
+
+    let trained = train_until_converged(m.clone(), &config, 3, &dev, &mut SilentObserver)?;
+    assert_eq!(accuracy(&trained.model)?, best);
+    assert!(best < 100.0);
+
+    let strict = TrainConfig {
+        accept_best: false,
+        ..config
+    };
+    assert!(matches!(
+        train_until_converged(m, &strict, 3, &dev, &mut SilentObserver),
+        Err(UtfRnnError::NotConverged { .. })
+    ));
+
+    Ok(())
+}
+
+/// Observer remembering everything it was told.
+#[derive(Default)]
+struct RecordingObserver {
+    epochs: Vec<EpochMetrics>,
+    attempts: Vec<usize>,
+    finished: Option<bool>,
+}
+
+impl TrainObserver for RecordingObserver {
+    fn on_epoch(&mut self, m: &EpochMetrics) {
+        self.epochs.push(m.clone());
+    }
+
+    fn on_attempt_start(&mut self, attempt: usize) {
+        self.attempts.push(attempt);
+    }
+
+    fn on_finish(&mut self, result: &crate::error::Result<()>) {
+        self.finished = Some(result.is_ok());
+    }
+}
+
+#[test]
+fn observer_sees_every_epoch_of_every_attempt() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let mut observer = RecordingObserver::default();
+    let trained = train_until_converged(m.clone(), &config, MAX_ATTEMPTS, &dev, &mut observer)?;
+    assert_eq!(observer.finished, Some(true));
+    let attempts = observer.attempts.len();
+    assert_eq!(observer.attempts, (1..attempts + 1).collect::<Vec<_>>());
+    // Every attempt restarts at epoch 1, and the last one produced the model.
+    let starts = observer.epochs.iter().filter(|e| e.epoch == 1).count();
+    assert_eq!(starts, attempts);
+    let last = &observer.epochs[observer.epochs.len() - trained.metrics.len()..];
+    assert_eq!(last, trained.metrics.as_slice());
+
+    // Failing runs are reported as such.
+    let mut observer = RecordingObserver::default();
+    let hopeless = TrainConfig {
+        epochs: 1,
+        ..config
+    };
+    let unlearnable = unlearnable_dataset(&dev)?;
+    assert!(train(unlearnable, &hopeless, &dev, &mut observer).is_err());
+    assert_eq!(observer.finished, Some(false));
+    assert_eq!(observer.attempts, [1]);
+    assert_eq!(observer.epochs.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn batched_evaluation_matches_unbatched() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        batch_size: Some(3),
+        seed: Some(4),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let model = fit(m.clone(), &config, &dev, None)?.trained.model;
+
+    let whole = evaluate(&model, &m.train_votes, &m.train_results)?;
+    for batch_size in [1, 3, 5, 8, 100] {
+        let batched = evaluate_batched(&model, &m.train_votes, &m.train_results, batch_size)?;
+        assert_eq!(batched, whole);
+        assert_eq!(batched.accuracy(), whole.accuracy());
+    }
+    assert_eq!(whole.total(), 8);
+
+    Ok(())
+}
+
+#[test]
+fn chunked_training_scores_match_unchunked() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        class_weights: Some(vec![2.0, 0.5]),
+        seed: Some(4),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let model = fit(m.clone(), &config, &dev, None)?.trained.model;
+    let class_weights = Tensor::new(&[2f32, 0.5], &dev)?;
+
+    let score = |batch_size| {
+        score_in_chunks(
+            &model,
+            &config,
+            &m.train_votes,
+            &m.train_results,
+            Some(&class_weights),
+            batch_size,
+        )
+    };
+    let whole = score(None)?;
+    for batch_size in [1, 3, 5, 8, 100] {
+        let chunked = score(Some(batch_size))?;
+        assert_eq!(chunked.accuracy, whole.accuracy);
+        assert_eq!(chunked.predictions, whole.predictions);
+        assert!((chunked.loss - whole.loss).abs() < 1e-5);
+    }
+    assert_eq!(whole.predictions.len(), 8);
+
+    // Training on a dataset without test rows fails instead of reporting NaN.
+    let mut untested = m.clone();
+    untested.test_votes = m.test_votes.narrow(0, 0, 0)?;
+    untested.test_results = m.test_results.narrow(0, 0, 0)?;
+    assert!(matches!(
+        fit(untested, &config, &dev, None),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn default_topology_parameter_count() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+
+    // 2 -> 4 -> 2 -> 2: (2*4 + 4) + (4*2 + 2) + (2*2 + 2)
+    assert_eq!(model.num_parameters(), 28);
+    let summary = model.summary();
+    assert_eq!(
+        summary.lines().collect::<Vec<_>>(),
+        [
+            "ln1: 2 -> 4 (12 params)",
+            "ln2: 4 -> 2 (10 params)",
+            "ln3: 2 -> 2 (6 params)",
+            "total: 28 params",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn accumulated_half_batches_match_the_full_batch() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let full = TrainConfig {
+        batch_size: Some(8),
+        epochs: 3,
+        seed: Some(12),
+        verbose: false,
+        ..Default::default()
+    };
+    let accumulated = TrainConfig {
+        batch_size: Some(4),
+        grad_accum_steps: 2,
+        ..full.clone()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let weights = |config: &TrainConfig| -> anyhow::Result<Vec<f32>> {
+        let varmap = fit(m.clone(), config, &dev, None)?.trained.varmap;
+        let vars = varmap.data().lock().unwrap();
+        let mut names: Vec<&String> = vars.keys().collect();
+        names.sort();
+        let mut values = Vec::new();
+        for name in names {
+            values.extend(vars[name].flatten_all()?.to_vec1::<f32>()?);
+        }
+        Ok(values)
+    };
+    // Two averaged half batches give the same gradient as one full batch.
+    for (a, b) in weights(&full)?.iter().zip(weights(&accumulated)?) {
+        assert!((a - b).abs() < 1e-5, "{a} != {b}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn regression_fits_a_linear_relationship() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        task: Task::Regression,
+        hidden_sizes: vec![8],
+        optimizer: Optimizer::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.0,
+        },
+        learning_rate: 0.02,
+        epochs: 400,
+        normalize: true,
+        seed: Some(3),
+        verbose: false,
+        ..Default::default()
+    };
+    // score = 0.5 * yes - 0.25 * no + 1 on a grid of vote counts.
+    let mut rows = Vec::new();
+    let mut scores = Vec::new();
+    for yes in 0..6 {
+        for no in 0..6 {
+            rows.push([yes as f32, no as f32]);
+            scores.push(0.5 * yes as f32 - 0.25 * no as f32 + 1.);
+        }
+    }
+    let votes = Tensor::from_slice(rows.as_flattened(), (rows.len(), 2), &dev)?;
+    let targets = Tensor::new(scores.as_slice(), &dev)?;
+    let m = Dataset {
+        train_votes: votes.clone(),
+        train_results: targets.clone(),
+        test_votes: votes.clone(),
+        test_results: targets,
+        validation: None,
+    };
+
+    let trained = fit(m, &config, &dev, None)?.trained;
+    assert_eq!(trained.model.num_classes()?, 1);
+    let rmse = trained.metrics.last().unwrap().test_rmse.unwrap();
+    assert!(rmse < 0.2, "rmse {rmse}");
+
+    let predicted = trained
+        .model
+        .predict_values(&Tensor::new(&[[4f32, 2.]], &dev)?)?;
+    assert!((predicted[0] - 2.5).abs() < 0.5, "{predicted:?}");
+
+    Ok(())
+}
+
+#[test]
+fn multi_label_predicts_overlapping_labels() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        task: Task::MultiLabel { num_labels: 2 },
+        hidden_sizes: vec![8],
+        optimizer: Optimizer::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.0,
+        },
+        epochs: 300,
+        normalize: true,
+        seed: Some(8),
+        verbose: false,
+        ..Default::default()
+    };
+    // Label 0 is set by many yes votes, label 1 by many no votes, so rows
+    // with both get both labels.
+    let votes = Tensor::new(
+        &[
+            [1f32, 1.],
+            [9., 1.],
+            [1., 9.],
+            [9., 9.],
+            [2., 2.],
+            [8., 2.],
+            [2., 8.],
+            [8., 8.],
+        ],
+        &dev,
+    )?;
+    let labels = Tensor::new(
+        &[
+            [0f32, 0.],
+            [1., 0.],
+            [0., 1.],
+            [1., 1.],
+            [0., 0.],
+            [1., 0.],
+            [0., 1.],
+            [1., 1.],
+        ],
+        &dev,
+    )?;
+    let m = Dataset {
+        train_votes: votes.clone(),
+        train_results: labels.clone(),
+        test_votes: votes.clone(),
+        test_results: labels.clone(),
+        validation: None,
+    };
+
+    let model = fit(m, &config, &dev, None)?.trained.model;
+    assert_eq!(model.forward(&votes)?.dims(), &[8, 2]);
+    let predicted = model.predict_labels(&votes)?;
+    assert_eq!(predicted[3], [true, true]);
+    assert_eq!(predicted[0], [false, false]);
+
+    let per_label = evaluate_multilabel(&model, &votes, &labels)?;
+    assert_eq!(per_label.len(), 2);
+    for matrix in &per_label {
+        assert_eq!(matrix.precision(1), 1.0);
+        assert_eq!(matrix.recall(1), 1.0);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn training_continues_past_full_accuracy() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        hidden_sizes: vec![8],
+        activation: Activation::Tanh,
+        optimizer: Optimizer::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.0,
+        },
+        epochs: 300,
+        normalize: true,
+        stop_on_accuracy: false,
+        seed: Some(7),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let metrics = fit(m.clone(), &config, &dev, None)?.trained.metrics;
+    assert_eq!(metrics.len(), 300);
+    let saturated = metrics
+        .iter()
+        .position(|e| e.test_accuracy == 100.0)
+        .expect("the test accuracy saturates");
+    assert!(saturated < 299);
+    assert!(metrics[299].train_loss < metrics[saturated].train_loss);
+
+    // `min_epochs` delays the accuracy stop.
+    let delayed = TrainConfig {
+        stop_on_accuracy: true,
+        min_epochs: saturated + 10,
+        ..config
+    };
+    let metrics = fit(m, &delayed, &dev, None)?.trained.metrics;
+    assert!(metrics.len() >= saturated + 10);
+
+    Ok(())
+}
+
+#[test]
+fn bias_free_model_has_fewer_parameters_and_trains() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        bias: false,
+        epochs: 30,
+        seed: Some(5),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let trained = fit(m.clone(), &config, &dev, None)?.trained;
+
+    // The 28 parameters of the default topology minus its 4 + 2 + 2 biases.
+    assert_eq!(trained.model.num_parameters(), 20);
+    assert!(trained
+        .model
+        .layers
+        .iter()
+        .all(|layer| layer.bias().is_none()));
+    let metrics = &trained.metrics;
+    assert!(metrics.iter().all(|e| e.train_loss.is_finite()));
+    assert!(metrics.last().unwrap().train_loss <= metrics[0].train_loss);
+
+    // The weights round-trip without any bias tensors.
+    let path = std::env::temp_dir().join("utf-rnn-bias-free.safetensors");
+    trained.model.save(&trained.varmap, &path)?;
+    let loaded = MultiLevelPerceptron::load(&path, &dev, &config)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(
+        loaded.predict(&m.test_votes)?,
+        trained.model.predict(&m.test_votes)?
+    );
+
+    Ok(())
+}
+
+/// Population variance of every element of `xs`.
+fn variance(xs: &Tensor) -> anyhow::Result<f32> {
+    let values = xs.flatten_all()?.to_vec1::<f32>()?;
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    Ok(values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32)
+}
+
+#[test]
+fn kaiming_init_scales_the_weight_variance_with_the_fan_in() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let (fan_in, fan_out) = (256, 128);
+    let expected = 2. / fan_in as f32;
+
+    // Drawn by candle through the var builder.
+    let varmap = VarMap::new();
+    let model = PerceptronBuilder::new()
+        .input_dim(fan_in)
+        .hidden(vec![fan_out])
+        .init(Init::KaimingUniform)
+        .build(VarBuilder::from_varmap(&varmap, DType::F32, &dev))?;
+    let weight = model.layers[0].weight();
+    assert!((variance(weight)? / expected - 1.).abs() < 0.1);
+    let bound = (6. / fan_in as f32).sqrt();
+    let weights = weight.flatten_all()?.to_vec1::<f32>()?;
+    assert!(weights.iter().all(|w| w.abs() <= bound));
+
+    // Redrawn from a seed.
+    init_seeded(&varmap, 3, Init::KaimingUniform)?;
+    assert!((variance(model.layers[0].weight())? / expected - 1.).abs() < 0.1);
+
+    // Xavier also accounts for the fan-out.
+    init_seeded(&varmap, 3, Init::XavierNormal)?;
+    let expected = 2. / (fan_in + fan_out) as f32;
+    assert!((variance(model.layers[0].weight())? / expected - 1.).abs() < 0.1);
+
+    Ok(())
+}
+
+#[test]
+fn seeded_init_draws_the_embedding_from_a_standard_normal() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let varmap = VarMap::new();
+    let model = PerceptronBuilder::new()
+        .input_mode(InputMode::Categorical {
+            vocab_size: 100,
+            embed_dim: 10,
+        })
+        .init(Init::KaimingUniform)
+        .build(VarBuilder::from_varmap(&varmap, DType::F32, &dev))?;
+
+    // Whatever the layers use, the embedding keeps unit variance.
+    init_seeded(&varmap, 3, Init::KaimingUniform)?;
+    let embeddings = model.embedding.as_ref().unwrap().embeddings();
+    assert!((variance(embeddings)? - 1.).abs() < 0.15);
+
+    Ok(())
+}
+
+#[test]
+fn two_fold_cross_validation_scores_every_fold() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 50,
+        seed: Some(2),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let votes = Tensor::cat(&[&m.train_votes, &m.test_votes], 0)?;
+    let labels = Tensor::cat(&[&m.train_results, &m.test_results], 0)?;
+
+    let accuracies = cross_validate(&votes, &labels, 2, &config, &dev)?;
+    assert_eq!(accuracies.len(), 2);
+    assert!(accuracies.iter().all(|a| (0.0..=100.0).contains(a)));
+    // A fixed seed makes the estimate reproducible.
+    assert_eq!(
+        accuracies,
+        cross_validate(&votes, &labels, 2, &config, &dev)?
+    );
+
+    assert!(matches!(
+        cross_validate(&votes, &labels, 1, &config, &dev),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn streaming_yields_the_same_windows_as_the_in_memory_loader() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let path = std::env::temp_dir().join("utf-rnn-stream.txt");
+    std::fs::write(&path, "über straße, öde ßüö über straße öde")?;
+    let (m, vocab) = Dataset::from_text_file_with_ratio(&path, 5, 1.0, &dev)?;
+
+    // Three-byte chunks split the two-byte characters across reads.
+    let stream = SequenceStream::open(&path, vocab.clone(), 5, 4, &dev)?.chunk_size(3);
+    let batches = stream.collect::<Result<Vec<_>, _>>()?;
+    let total: usize = batches
+        .iter()
+        .map(|(inputs, _)| inputs.dim(0).unwrap())
+        .sum();
+    assert_eq!(total, m.train_votes.dim(0)?);
+    assert!(batches
+        .iter()
+        .all(|(inputs, _)| inputs.dim(0).unwrap() <= 4));
+
+    let inputs = Tensor::cat(&batches.iter().map(|b| &b.0).collect::<Vec<_>>(), 0)?;
+    let targets = Tensor::cat(&batches.iter().map(|b| &b.1).collect::<Vec<_>>(), 0)?;
+    assert_eq!(inputs.to_vec2::<u32>()?, m.train_votes.to_vec2::<u32>()?);
+    assert_eq!(targets.to_vec2::<u32>()?, m.train_results.to_vec2::<u32>()?);
+
+    // The char RNN trains on the stream batch by batch.
+    let varmap = VarMap::new();
+    let rnn = CharRnn::new(
+        vocab.len(),
+        8,
+        16,
+        VarBuilder::from_varmap(&varmap, DType::F32, &dev),
+    )?;
+    let stream = SequenceStream::open(&path, vocab, 5, 4, &dev)?;
+    let losses = char_rnn::train_stream(&rnn, &varmap, stream, 0.02, &dev)?;
+    assert_eq!(losses.len(), batches.len());
+    assert!(losses.iter().all(|loss| loss.is_finite()));
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn warmup_ramps_the_learning_rate_up_to_the_base() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 12,
+        learning_rate: 0.05,
+        warmup_epochs: 4,
+        lr_schedule: LrSchedule::Cosine { min_lr: 0.001 },
+        seed: Some(2),
+        verbose: false,
+        ..Default::default()
+    };
+    assert_eq!(config.learning_rate_at(0), 0.0);
+
+    // An unlearnable test set keeps training from stopping before the last epoch.
+    let m = unlearnable_dataset(&dev)?;
+
+    let rates: Vec<f64> = fit(m, &config, &dev, None)?
+        .trained
+        .metrics
+        .iter()
+        .map(|e| e.learning_rate)
+        .collect();
+    assert_eq!(rates.len(), config.epochs);
+    assert!(rates[0] <= 0.25 * config.learning_rate);
+    assert!(rates[..4].windows(2).all(|pair| pair[0] < pair[1]));
+    assert!((rates[3] - config.learning_rate).abs() < 1e-12);
+    // The cosine schedule starts over from the base rate after the warm-up.
+    assert!((rates[4] - config.learning_rate).abs() < 1e-12);
+    assert!((rates.last().unwrap() - 0.001).abs() < 1e-12);
+
+    Ok(())
+}
+
+#[test]
+fn four_feature_dataset_sets_the_model_width() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    // The default config still describes two vote columns.
+    let config = TrainConfig {
+        epochs: 5,
+        seed: Some(1),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = Dataset {
+        train_votes: Tensor::new(
+            &[
+                [1f32, 0., 3., 2.],
+                [0., 1., 2., 3.],
+                [4., 1., 0., 0.],
+                [1., 4., 0., 1.],
+            ],
+            &dev,
+        )?,
+        train_results: Tensor::new(&[1u32, 0, 1, 0], &dev)?,
+        test_votes: Tensor::new(&[[2f32, 1., 1., 0.], [1., 2., 0., 1.]], &dev)?,
+        test_results: Tensor::new(&[1u32, 0], &dev)?,
+        validation: None,
+    };
+    assert_eq!(m.input_dim()?, 4);
+
+    let trained = fit(m.clone(), &config, &dev, None)?.trained;
+    assert_eq!(trained.model.input_dim(), 4);
+    assert_eq!(trained.model.predict(&m.test_votes)?.len(), 2);
+    let batch = trained.model.predict_batch(&[vec![1., 0., 3., 2.]])?;
+    assert_eq!(batch.len(), 1);
+    assert!(trained.model.predict_batch(&[vec![1., 0.]]).is_err());
+
+    // Test votes of another width are rejected up front.
+    let narrow = Dataset {
+        test_votes: Tensor::new(&[[2f32, 1.]], &dev)?,
+        test_results: Tensor::new(&[1u32], &dev)?,
+        ..m
+    };
+    assert!(matches!(
+        narrow.input_dim(),
+        Err(UtfRnnError::ShapeMismatch { .. })
+    ));
+
+    Ok(())
+}
+
+/// Splits a protobuf message into `(field number, value)` pairs, with varints
+/// as `Ok` and length-delimited values as `Err` — all an ONNX file uses.
+fn proto_fields(mut buf: &[u8]) -> Vec<(u64, Result<u64, &[u8]>)> {
+    fn varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let byte = buf[0];
+            *buf = &buf[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = varint(&mut buf);
+        let value = match key & 7 {
+            0 => Ok(varint(&mut buf)),
+            2 => {
+                let len = varint(&mut buf) as usize;
+                let (bytes, rest) = buf.split_at(len);
+                buf = rest;
+                Err(bytes)
+            }
+            wire_type => panic!("unexpected wire type {wire_type}"),
+        };
+        fields.push((key >> 3, value));
+    }
+    fields
+}
+
+/// The length-delimited values of field `field` of `message`.
+fn proto_messages(message: &[u8], field: u64) -> Vec<&[u8]> {
+    proto_fields(message)
+        .into_iter()
+        .filter(|(number, _)| *number == field)
+        .filter_map(|(_, value)| value.err())
+        .collect()
+}
+
+#[test]
+fn onnx_export_has_a_gemm_per_layer_and_named_io() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let varmap = VarMap::new();
+    let vs = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let model = PerceptronBuilder::new()
+        .input_dim(3)
+        .hidden(vec![6, 5, 4])
+        .num_classes(2)
+        .build(vs)?;
+
+    let path = std::env::temp_dir().join("utf-rnn-export.onnx");
+    model.export_onnx(&varmap, &path)?;
+    let bytes = std::fs::read(&path)?;
+    std::fs::remove_file(&path)?;
+
+    let graphs = proto_messages(&bytes, 7);
+    assert_eq!(graphs.len(), 1);
+    let graph = graphs[0];
+    let name = |value_info: &[u8]| -> anyhow::Result<String> {
+        Ok(String::from_utf8(
+            proto_messages(value_info, 1)[0].to_vec(),
+        )?)
+    };
+    let inputs = proto_messages(graph, 11);
+    let outputs = proto_messages(graph, 12);
+    assert_eq!(inputs.len(), 1);
+    assert_eq!(outputs.len(), 1);
+    assert_eq!(name(inputs[0])?, crate::onnx::ONNX_INPUT);
+    assert_eq!(name(outputs[0])?, crate::onnx::ONNX_OUTPUT);
+
+    let op_types: Vec<&str> = proto_messages(graph, 1)
+        .into_iter()
+        .map(|node| std::str::from_utf8(proto_messages(node, 4)[0]))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(
+        op_types,
+        ["Gemm", "Relu", "Gemm", "Relu", "Gemm", "Relu", "Gemm"]
+    );
+    // A weight and a bias per layer, with every value stored.
+    let initializers = proto_messages(graph, 5);
+    assert_eq!(initializers.len(), 8);
+    let raw_len: usize = initializers
+        .iter()
+        .map(|tensor| proto_messages(tensor, 9)[0].len())
+        .sum();
+    assert_eq!(raw_len, model.num_parameters() * 4);
+
+    Ok(())
+}
+
+#[test]
+fn config_round_trips_through_a_toml_file() -> anyhow::Result<()> {
+    let config = TrainConfig {
+        task: Task::MultiLabel { num_labels: 3 },
+        hidden_sizes: vec![8, 4],
+        activation: Activation::Gelu,
+        learning_rate: 0.01,
+        lr_schedule: LrSchedule::Cosine { min_lr: 1e-4 },
+        optimizer: Optimizer::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.01,
+        },
+        class_weights: Some(vec![1.0, 2.5]),
+        seed: Some(42),
+        early_stopping: Some(EarlyStopping {
+            patience: 3,
+            min_delta: 0.001,
+        }),
+        dtype: DType::BF16,
+        ..Default::default()
+    };
+
+    let path = std::env::temp_dir().join("utf-rnn-config.toml");
+    std::fs::write(&path, toml::to_string(&config)?)?;
+    let loaded = TrainConfig::from_file(&path)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(format!("{loaded:?}"), format!("{config:?}"));
+
+    // Missing fields keep their defaults.
+    let path = std::env::temp_dir().join("utf-rnn-config.json");
+    std::fs::write(&path, r#"{ "epochs": 7, "dtype": "f16" }"#)?;
+    let loaded = TrainConfig::from_file(&path)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(loaded.epochs, 7);
+    assert_eq!(loaded.dtype, DType::F16);
+    assert_eq!(loaded.hidden_sizes, TrainConfig::default().hidden_sizes);
+
+    Ok(())
+}
+
+#[test]
+fn per_class_accuracy_weighted_by_class_counts_is_the_accuracy() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        num_classes: 3,
+        hidden_sizes: vec![8],
+        epochs: 20,
+        stop_on_accuracy: false,
+        seed: Some(3),
+        verbose: false,
+        ..Default::default()
+    };
+    let votes = Tensor::new(
+        &[
+            [1f32, 1.],
+            [2., 1.],
+            [10., 1.],
+            [11., 2.],
+            [1., 10.],
+            [2., 11.],
+        ],
+        &dev,
+    )?;
+    let results = Tensor::new(&[0u32, 0, 1, 1, 2, 2], &dev)?;
+    // An imbalanced test set: four samples of class 0, two of 1, one of 2.
+    let test_votes = Tensor::new(
+        &[
+            [1f32, 2.],
+            [2., 2.],
+            [1., 1.],
+            [3., 1.],
+            [10., 2.],
+            [12., 1.],
+            [1., 11.],
+        ],
+        &dev,
+    )?;
+    let class_counts = [4f32, 2., 1.];
+    let m = Dataset {
+        train_votes: votes,
+        train_results: results,
+        test_votes,
+        test_results: Tensor::new(&[0u32, 0, 0, 0, 1, 1, 2], &dev)?,
+        validation: None,
+    };
+
+    let metrics = fit(m, &config, &dev, None)?.trained.metrics;
+    assert_eq!(metrics.len(), 20);
+    for epoch in &metrics {
+        assert_eq!(epoch.per_class_accuracy.len(), 3);
+        assert!(epoch
+            .per_class_accuracy
+            .iter()
+            .all(|a| (0.0..=1.0).contains(a)));
+        let correct: f32 = epoch
+            .per_class_accuracy
+            .iter()
+            .zip(class_counts)
+            .map(|(accuracy, count)| accuracy * count)
+            .sum();
+        assert!((100. * correct / 7. - epoch.test_accuracy).abs() < 1e-3);
+    }
+
+    Ok(())
+}
+
+/// The sample dataset with a config that validates against it.
+fn valid_setup(dev: &Device) -> anyhow::Result<(Dataset, TrainConfig)> {
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    Ok((sample_dataset(dev)?, config))
+}
+
+#[test]
+fn validate_setup_accepts_the_sample_dataset() -> anyhow::Result<()> {
+    let (m, config) = valid_setup(&Device::Cpu)?;
+    validate_setup(&m, &config)?;
+
+    Ok(())
+}
+
+#[test]
+fn validate_setup_rejects_mismatched_shapes() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let (m, config) = valid_setup(&dev)?;
+
+    // Test votes one column wider than the training votes.
+    let mut wide = m.clone();
+    wide.test_votes = Tensor::zeros((3, 3), DType::F32, &dev)?;
+    assert!(matches!(
+        validate_setup(&wide, &config),
+        Err(UtfRnnError::ShapeMismatch { name, .. }) if name == "test votes"
+    ));
+
+    // One label too few.
+    let mut short = m;
+    short.test_results = short.test_results.narrow(0, 0, 2)?;
+    assert!(matches!(
+        validate_setup(&short, &config),
+        Err(UtfRnnError::ShapeMismatch { name, .. }) if name == "test results"
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn validate_setup_rejects_float_class_labels() -> anyhow::Result<()> {
+    let (mut m, config) = valid_setup(&Device::Cpu)?;
+    m.train_results = m.train_results.to_dtype(DType::F32)?;
+    assert!(matches!(
+        validate_setup(&m, &config),
+        Err(UtfRnnError::DTypeMismatch {
+            expected: DType::U32,
+            got: DType::F32,
+            ..
+        })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn validate_setup_rejects_labels_past_the_last_class() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let (mut m, config) = valid_setup(&dev)?;
+    m.test_results = Tensor::new(&[1u32, 0, 2], &dev)?;
+    assert!(matches!(
+        validate_setup(&m, &config),
+        Err(UtfRnnError::LabelOutOfRange {
+            label: 2,
+            num_classes: 2,
+            ..
+        })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn validate_setup_rejects_invalid_configs() -> anyhow::Result<()> {
+    let (m, config) = valid_setup(&Device::Cpu)?;
+    let empty_layer = TrainConfig {
+        hidden_sizes: vec![4, 0],
+        ..config.clone()
+    };
+    assert!(matches!(
+        validate_setup(&m, &empty_layer),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+    let wrong_weights = TrainConfig {
+        class_weights: Some(vec![1.0; 3]),
+        ..config
+    };
+    assert!(matches!(
+        validate_setup(&m, &wrong_weights),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+
+    Ok(())
+}
+
+/// The `check` command resolves its device before validating, so an
+/// unreachable one fails there, telling a missing feature apart from a
+/// missing device.
+#[test]
+fn check_rejects_an_unavailable_device() -> anyhow::Result<()> {
+    let spec: DeviceSpec = "cuda:99".parse()?;
+    let err = resolve_device(&spec).unwrap_err();
+    assert!(matches!(err, UtfRnnError::Device(_)));
+    let expected = if !cfg!(feature = "cuda") {
+        "without the cuda feature"
+    } else if !candle_core::utils::cuda_is_available() {
+        "no CUDA device is available"
+    } else {
+        "cannot open CUDA device 99"
+    };
+    assert!(err.to_string().contains(expected), "{err}");
+
+    Ok(())
+}
+
+#[test]
+fn loss_ema_lies_between_the_raw_losses_and_settles() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 20,
+        stop_on_accuracy: false,
+        ema_alpha: 0.3,
+        seed: Some(2),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let metrics = fit(m.clone(), &config, &dev, None)?.trained.metrics;
+    assert_eq!(metrics[0].train_loss_ema, metrics[0].train_loss);
+    for pair in metrics.windows(2) {
+        // Every average lies between the previous average and the new loss,
+        // starting with the first two raw losses.
+        let (lo, hi) = if pair[0].train_loss_ema < pair[1].train_loss {
+            (pair[0].train_loss_ema, pair[1].train_loss)
+        } else {
+            (pair[1].train_loss, pair[0].train_loss_ema)
+        };
+        assert!((lo - 1e-6..=hi + 1e-6).contains(&pair[1].train_loss_ema));
+    }
+
+    // Without any learning the loss stays put and so does its average.
+    let frozen = TrainConfig {
+        learning_rate: 0.0,
+        ..config
+    };
+    for epoch in fit(m, &frozen, &dev, None)?.trained.metrics {
+        assert!((epoch.train_loss_ema - epoch.train_loss).abs() < 1e-6);
+    }
+    // From any start, the average converges to a constant loss.
+    let average = (0..200).fold(5.0, |average, _| ema(average, 1.0, 0.1));
+    assert!((average - 1.0).abs() < 1e-6);
+
+    Ok(())
+}
+
+#[test]
+fn param_groups_move_the_head_faster_than_the_base() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let varmap = VarMap::new();
+    let vs = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let base = vs.pp("ln1").get((2, 2), "weight")?;
+    let head = vs.pp("ln3").get((2, 2), "weight")?;
+    let before = (base.copy()?, head.copy()?);
+    let config = TrainConfig {
+        learning_rate: 0.01,
+        param_groups: vec![("ln3".to_string(), 0.1)],
+        ..Default::default()
+    };
+
+    // Both weights get a gradient of one everywhere.
+    let grads = (base.sum_all()? + head.sum_all()?)?.backward()?;
+    let mut optimizers = group_optimizers(&varmap, &config)?;
+    assert_eq!(optimizers.len(), 2);
+    for (_, optimizer) in &mut optimizers {
+        optimizer.step(&grads)?;
+    }
+
+    let change = |before: &Tensor, after: &Tensor| -> anyhow::Result<f32> {
+        Ok((before - after)?.abs()?.mean_all()?.to_scalar::<f32>()?)
+    };
+    let base_change = change(&before.0, &base)?;
+    let head_change = change(&before.1, &head)?;
+    assert!((base_change - 0.01).abs() < 1e-5);
+    assert!((head_change - 0.1).abs() < 1e-5);
+    assert_eq!(config.group_learning_rate_at(0.1, 1), 0.1);
+
+    Ok(())
+}
+
+#[test]
+fn weight_stats_are_finite_and_follow_training() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        learning_rate: 0.1,
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let mut optimizers = group_optimizers(&varmap, &config)?;
+
+    let mut history = Vec::new();
+    for _ in 0..2 {
+        let stats = model.weight_stats()?;
+        let names: Vec<&str> = stats.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "ln1.weight",
+                "ln1.bias",
+                "ln2.weight",
+                "ln2.bias",
+                "ln3.weight",
+                "ln3.bias"
+            ]
+        );
+        for s in &stats {
+            assert!([s.min, s.max, s.mean, s.std].iter().all(|v| v.is_finite()));
+            assert!(s.min <= s.mean && s.mean <= s.max);
+            assert!(s.std >= 0.0);
+        }
+        history.push(stats);
+
+        let logits = model.forward(&m.train_votes)?;
+        let grads = loss::cross_entropy(&logits, &m.train_results)?.backward()?;
+        for (_, optimizer) in &mut optimizers {
+            optimizer.step(&grads)?;
+        }
+    }
+    assert_ne!(history[0], history[1]);
+
+    Ok(())
+}
+
+#[test]
+fn predictions_csv_has_a_header_and_a_row_per_sample() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        verbose: false,
+        ..Default::default()
+    };
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let votes = Tensor::new(&[[13f32, 22.], [30., 20.], [3., 10.]], &dev)?;
+    let preds = model.predict(&votes)?;
+    let probs = model.predict_proba(&votes)?;
+
+    let path = std::env::temp_dir().join("utf-rnn-predictions.csv");
+    write_predictions_csv(&path, &votes, &preds, Some(&probs))?;
+    let text = std::fs::read_to_string(&path)?;
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "x0,x1,prediction,p0,p1");
+    assert_eq!(
+        lines[1].split(',').nth(2),
+        Some(preds[0].to_string().as_str())
+    );
+    assert!(lines[1..].iter().all(|line| line.split(',').count() == 5));
+
+    // Without probabilities only the votes and the class are written.
+    write_predictions_csv(&path, &votes, &preds, None)?;
+    let text = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(text.lines().next(), Some("x0,x1,prediction"));
+    assert_eq!(text.lines().count(), 4);
+
+    // The predictions must match the inputs row for row.
+    assert!(matches!(
+        write_predictions_csv(&path, &votes, &preds[..2], None),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+
+    Ok(())
+}
+
+#[cfg(feature = "debug-tools")]
+#[test]
+fn default_model_passes_the_gradient_check() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    init_seeded(&varmap, 7, Init::Default)?;
+    // Small inputs keep the finite differences away from the ReLU kinks.
+    let votes = Tensor::new(&[[0.3f32, -0.2], [-0.5, 0.4]], &dev)?;
+    let labels = Tensor::new(&[1u32, 0], &dev)?;
+
+    let error = crate::gradcheck::gradcheck(&model, &votes, &labels)?;
+    assert!(error < 0.05, "max relative gradient error {error}");
+
+    Ok(())
+}
+
+#[test]
+fn model_separates_the_synthetic_dataset() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let m = Dataset::synthetic_linearly_separable(200, 3, 11, &dev)?;
+    assert_eq!(m.train_votes.dims(), &[160, 3]);
+    assert_eq!(m.test_results.dims(), &[40]);
+    // The same seed always generates the same rows.
+    let again = Dataset::synthetic_linearly_separable(200, 3, 11, &dev)?;
+    assert_eq!(
+        again.train_votes.to_vec2::<f32>()?,
+        m.train_votes.to_vec2::<f32>()?
+    );
+
+    let config = TrainConfig {
+        hidden_sizes: vec![16],
+        optimizer: Optimizer::AdamW {
+            beta1: 0.9,
+            beta2: 0.999,
+            weight_decay: 0.0,
+        },
+        epochs: 200,
+        seed: Some(4),
+        verbose: false,
+        ..Default::default()
+    };
+    let outcome = fit(m, &config, &dev, None)?;
+    assert!(outcome.accuracy >= 95.0, "accuracy {}", outcome.accuracy);
+
+    Ok(())
+}
+
+#[test]
+fn parse_votes_reads_a_comma_separated_line() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let votes = parse_votes(" 13, 22.5\n", &dev)?;
+    assert_eq!(votes.to_vec2::<f32>()?, [[13.0, 22.5]]);
+
+    for malformed in ["13, abc", "", "13,,22"] {
+        assert!(matches!(
+            parse_votes(malformed, &dev),
+            Err(UtfRnnError::InvalidInput(_))
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn char_transformer_generates_and_trains() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let path = std::env::temp_dir().join("utf-rnn-transformer.txt");
+    std::fs::write(&path, "abcdabcdabcdabcdabcdabcdabcdabcdabcdabcd")?;
+    let (m, vocab) = Dataset::from_text_file(&path, 6, &dev)?;
+    std::fs::remove_file(&path)?;
+
+    let varmap = VarMap::new();
+    let vs = VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    let model = CharTransformer::new(vocab.len(), 8, 2, vs.clone())?;
+    assert!(CharTransformer::new(vocab.len(), 8, 3, vs.pp("other")).is_err());
+
+    assert_eq!(model.forward(&m.test_votes)?.dims()[2], vocab.len());
+    for temperature in [0.0, 0.8] {
+        let text = model.generate(&vocab, "ab", 10, temperature, Some(0.9))?;
+        assert!(text.starts_with("ab"));
+        assert_eq!(text.chars().count(), 2 + 10);
+        assert!(text.chars().all(|c| vocab.id(c).is_some()));
+    }
+
+    let sequences = Tensor::cat(&[&m.test_votes, &m.test_results.narrow(1, 5, 1)?], 1)?;
+    let untrained = perplexity(&model, &sequences, &dev)?;
+    let perplexities = char_rnn::train(&model, &varmap, &m, 30, 0.02, &dev)?;
+    assert_eq!(perplexities.len(), 30);
+    assert!(perplexities.last().unwrap() < &untrained);
+
+    Ok(())
+}
+
+#[test]
+fn grad_norm_is_reported_for_every_epoch() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 5,
+        stop_on_accuracy: false,
+        seed: Some(1),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let metrics = fit(m.clone(), &config, &dev, None)?.trained.metrics;
+    assert!(metrics.iter().all(|e| e.grad_norm.is_finite()));
+    assert!(metrics[0].grad_norm > 0.0);
+
+    // The reported norm is taken before clipping.
+    let clipped = TrainConfig {
+        grad_clip: Some(1e-6),
+        ..config
+    };
+    let metrics = fit(m, &clipped, &dev, None)?.trained.metrics;
+    assert!(metrics[0].grad_norm > 1e-6);
+
+    Ok(())
+}
+
+#[test]
+fn frozen_layers_keep_their_weights() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 5,
+        stop_on_accuracy: false,
+        seed: Some(3),
+        frozen: vec!["ln1".to_string()],
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let weight = |varmap: &VarMap, name: &str| -> anyhow::Result<Vec<f32>> {
+        let vars = varmap.data().lock().unwrap();
+        Ok(vars[name].as_tensor().flatten_all()?.to_vec1::<f32>()?)
+    };
+
+    // The same seed draws the same initial weights with and without training.
+    let untrained = fit(
+        m.clone(),
+        &TrainConfig {
+            epochs: 0,
+            ..config.clone()
+        },
+        &dev,
+        None,
+    )?
+    .trained;
+    let trained = fit(m.clone(), &config, &dev, None)?.trained;
+    for name in ["ln1.weight", "ln1.bias"] {
+        let before: Vec<u32> = weight(&untrained.varmap, name)?
+            .iter()
+            .map(|v| v.to_bits())
+            .collect();
+        let after: Vec<u32> = weight(&trained.varmap, name)?
+            .iter()
+            .map(|v| v.to_bits())
+            .collect();
+        assert_eq!(before, after, "{name} changed");
+    }
+    assert_ne!(
+        weight(&untrained.varmap, "ln3.weight")?,
+        weight(&trained.varmap, "ln3.weight")?
+    );
+
+    let unknown = TrainConfig {
+        frozen: vec!["ln9".to_string()],
+        ..config
+    };
+    assert!(matches!(
+        fit(m, &unknown, &dev, None),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn label_smoothing_penalizes_confident_predictions() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    // Confident and correct for both rows.
+    let logits = Tensor::new(&[[4f32, -4., 0.], [-3., 5., 1.]], &dev)?;
+    let labels = Tensor::new(&[0u32, 1], &dev)?;
+    let loss = |smoothing: f32| -> anyhow::Result<f32> {
+        Ok(Loss::CrossEntropy
+            .compute_smoothed(&logits, &labels, None, smoothing)?
+            .to_scalar::<f32>()?)
+    };
+
+    let plain = Loss::CrossEntropy
+        .compute(&logits, &labels)?
+        .to_scalar::<f32>()?;
+    assert_eq!(loss(0.0)?, plain);
+
+    // Against the targets (1 - 0.3) * one_hot + 0.3 / 3.
+    let log_probs = ops::log_softmax(&logits, D::Minus1)?.to_vec2::<f32>()?;
+    let expected: f32 = log_probs
+        .iter()
+        .zip([0, 1])
+        .map(|(row, label)| {
+            -row.iter()
+                .enumerate()
+                .map(|(c, lp)| lp * if c == label { 0.8 } else { 0.1 })
+                .sum::<f32>()
+        })
+        .sum::<f32>()
+        / 2.0;
+    let smoothed = loss(0.3)?;
+    assert!((smoothed - expected).abs() < 1e-5);
+    assert!(smoothed > plain);
+
+    Ok(())
+}
+
+#[test]
+fn loaded_model_scores_like_the_trained_one() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        seed: Some(8),
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let outcome = fit(m.clone(), &config, &dev, None)?;
+    let trained = &outcome.trained;
+
+    let path = std::env::temp_dir().join("utf-rnn-score.safetensors");
+    trained.model.save(&trained.varmap, &path)?;
+    let loaded = MultiLevelPerceptron::load(&path, &dev, &config)?;
+    std::fs::remove_file(&path)?;
+
+    let report = loaded.score(&m)?;
+    assert_eq!(report.accuracy, outcome.accuracy);
+    assert_eq!(
+        report.confusion,
+        evaluate(&trained.model, &m.test_votes, &m.test_results)?
+    );
+    assert_eq!(report.confusion.total(), m.test_results.dims1()?);
+    let last = trained.metrics.last().unwrap();
+    assert!((report.loss - last.test_loss).abs() < 1e-5);
+    // Scoring leaves the model as it was.
+    assert_eq!(loaded.score(&m)?, report);
+
+    Ok(())
+}
+
+#[test]
+fn score_rejects_regression_and_multi_label_models() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let m = sample_dataset(&dev)?;
+
+    let varmap = VarMap::new();
+    let regression = PerceptronBuilder::new()
+        .num_classes(1)
+        .build(VarBuilder::from_varmap(&varmap, DType::F32, &dev))?;
+    let mut values = m.clone();
+    values.test_results = Tensor::new(&[0.5f32, 1.5, 2.5], &dev)?;
+    assert!(matches!(
+        regression.score(&values),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+
+    let varmap = VarMap::new();
+    let multi_label =
+        PerceptronBuilder::new().build(VarBuilder::from_varmap(&varmap, DType::F32, &dev))?;
+    let mut flags = m;
+    flags.test_results = Tensor::new(&[[1u32, 0], [1, 1], [0, 1]], &dev)?;
+    assert!(matches!(
+        multi_label.score(&flags),
+        Err(UtfRnnError::InvalidInput(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn saliency_has_the_input_shape() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    init_seeded(&varmap, 5, Init::Default)?;
+    let votes = Tensor::new(&[[13f32, 22.], [30., 20.], [3., 10.]], &dev)?;
+
+    let saliency = model.saliency(&votes)?;
+    assert_eq!(saliency.dims(), votes.dims());
+    let values = saliency.flatten_all()?.to_vec1::<f32>()?;
+    assert!(values.iter().all(|v| v.is_finite() && *v >= 0.0));
+    // The votes themselves are left alone.
+    assert_eq!(votes.to_vec2::<f32>()?[0], [13., 22.]);
+
+    Ok(())
+}
+
+#[test]
+fn json_lines_observer_writes_every_epoch() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 5,
+        stop_on_accuracy: false,
+        verbose: false,
+        seed: Some(3),
+        ..Default::default()
+    };
+    let mut observer = JsonLinesObserver::new(Vec::new());
+    let trained = train(sample_dataset(&dev)?, &config, &dev, &mut observer)?;
+
+    let output = String::from_utf8(observer.finish()?)?;
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), trained.metrics.len());
+    for (line, metrics) in lines.iter().zip(&trained.metrics) {
+        let value: serde_json::Value = serde_json::from_str(line)?;
+        assert_eq!(value["epoch"], metrics.epoch);
+        for field in [
+            "train_loss",
+            "train_loss_ema",
+            "test_loss",
+            "val_loss",
+            "test_accuracy",
+            "train_accuracy",
+            "per_class_accuracy",
+            "confusion",
+            "test_rmse",
+            "learning_rate",
+            "grad_norm",
+        ] {
+            assert!(value.get(field).is_some(), "{field} is missing from {line}");
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn train_accuracy_is_reported_only_when_enabled() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 20,
+        stop_on_accuracy: false,
+        dropout: 0.5,
+        verbose: false,
+        seed: Some(2),
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+
+    let without = train(m.clone(), &config, &dev, &mut SilentObserver)?;
+    assert!(without.metrics.iter().all(|e| e.train_accuracy.is_none()));
+
+    let config = TrainConfig {
+        compute_train_accuracy: true,
+        ..config
+    };
+    let trained = train(m.clone(), &config, &dev, &mut SilentObserver)?;
+    assert_eq!(trained.metrics.len(), 20);
+    for epoch in &trained.metrics {
+        let train_accuracy = epoch.train_accuracy.expect("train accuracy is enabled");
+        assert!((0.0..=100.0).contains(&train_accuracy));
+        assert!((0.0..=100.0).contains(&epoch.test_accuracy));
+    }
+    // Without dropout at scoring time the last epoch matches a fresh evaluation.
+    let last = trained.metrics.last().unwrap().train_accuracy.unwrap();
+    let confusion = evaluate(&trained.model, &m.train_votes, &m.train_results)?;
+    assert!((last - 100. * confusion.accuracy()).abs() < 1e-3);
+
+    Ok(())
+}
+
+#[test]
+fn predict_u32_matches_the_manual_tensor_path() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        normalize: true,
+        seed: Some(6),
+        verbose: false,
+        ..Default::default()
+    };
+    let trained = fit(sample_dataset(&dev)?, &config, &dev, None)?.trained;
+    let predictor = Predictor::new(trained, &config, None)?;
+
+    let rows = vec![vec![13u32, 22], vec![30, 20], vec![3, 10]];
+    let votes = Tensor::from_vec(rows.concat(), (rows.len(), config.vote_dim), &dev)?
+        .to_dtype(DType::F32)?;
+    assert_eq!(
+        predictor.predict_u32(&rows)?,
+        predictor.model().predict(&votes)?
+    );
+    assert!(predictor.predict_u32(&[])?.is_empty());
+    assert!(matches!(
+        predictor.predict_u32(&[vec![1, 2, 3]]),
+        Err(UtfRnnError::ShapeMismatch { .. })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn weighted_sampler_balances_the_classes() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    // 90 rows of class 0, 10 of class 1.
+    let labels: Vec<u32> = (0..100).map(|i| u32::from(i >= 90)).collect();
+    let results = Tensor::from_vec(labels, 100, &dev)?;
+    let sampler = WeightedSampler::new(&results, 2)?;
+
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut counts = [0usize; 2];
+    for _ in 0..50 {
+        for row in sampler.epoch_indices(&mut rng) {
+            counts[usize::from(row >= 90)] += 1;
+        }
+    }
+    // The sampled share of the rare class is closer to uniform than its share of the labels.
+    let labels = results.to_vec1::<u32>()?;
+    let raw_share = labels.iter().filter(|&&label| label == 1).count() as f32 / labels.len() as f32;
+    let sampled_share = counts[1] as f32 / (counts[0] + counts[1]) as f32;
+    assert!(
+        (sampled_share - 0.5).abs() < (raw_share - 0.5).abs(),
+        "sampled {sampled_share}, raw {raw_share}"
+    );
+
+    // Training with it runs through every epoch.
+    let config = TrainConfig {
+        balanced_sampling: true,
+        batch_size: Some(2),
+        epochs: 5,
+        stop_on_accuracy: false,
+        verbose: false,
+        seed: Some(1),
+        ..Default::default()
+    };
+    let trained = fit(sample_dataset(&dev)?, &config, &dev, None)?.trained;
+    assert_eq!(trained.metrics.len(), 5);
+
+    Ok(())
+}
+
+#[cfg(feature = "bench")]
+#[test]
+fn bench_reports_positive_throughput() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        seed: Some(1),
+        ..Default::default()
+    };
+    let results = crate::bench::bench_train(&sample_dataset(&dev)?, &config, 3)?;
+
+    assert_eq!(results[0].device, "cpu");
+    for result in &results {
+        assert_eq!(result.epochs, 3);
+        assert!(result.epochs_per_sec > 0.0);
+    }
+    let table = crate::bench::comparison_table(&results);
+    assert_eq!(table.lines().count(), results.len() + 1);
+
+    Ok(())
+}
+
+#[test]
+fn vote_counts_must_fill_whole_rows() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let m = Dataset::from_vote_counts(&[1, 2, 3, 4], &[0, 1], &[5, 6], &[1], VOTE_DIM, &dev)?;
+    assert_eq!(m.train_votes.dims(), &[2, VOTE_DIM]);
+    assert_eq!(m.train_votes.dtype(), DType::F32);
+
+    let empty = Dataset::from_vote_counts(&[], &[], &[5, 6], &[1], VOTE_DIM, &dev);
+    assert!(matches!(
+        empty,
+        Err(UtfRnnError::ShapeMismatch { ref name, .. }) if name == "train votes"
+    ));
+
+    let ragged =
+        Dataset::from_vote_counts(&[1, 2, 3, 4], &[0, 1], &[5, 6, 7], &[1], VOTE_DIM, &dev);
+    assert!(matches!(
+        ragged,
+        Err(UtfRnnError::ShapeMismatch { ref name, ref got, .. })
+            if name == "test votes" && got == &[3]
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn search_architectures_scores_every_candidate() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 30,
+        verbose: false,
+        ..Default::default()
+    };
+    let m = sample_dataset(&dev)?;
+    let candidates = vec![vec![4, 2], vec![8]];
+
+    let scores = search_architectures(&m, candidates.clone(), &config, &dev)?;
+    assert_eq!(scores.len(), 2);
+    for candidate in &candidates {
+        assert!(scores
+            .iter()
+            .any(|(hidden_sizes, _)| hidden_sizes == candidate));
+    }
+    assert!(scores[0].1 >= scores[1].1);
+    assert!(scores
+        .iter()
+        .all(|(_, accuracy)| (0.0..=100.0).contains(accuracy)));
+    // The fixed seed makes the search reproducible.
+    assert_eq!(search_architectures(&m, candidates, &config, &dev)?, scores);
+
+    Ok(())
+}
+
+#[test]
+fn reinit_redraws_the_weights_by_seed() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let mut varmap = VarMap::new();
+    let mut model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let weights = |model: &MultiLevelPerceptron| -> anyhow::Result<Vec<Vec<Vec<f32>>>> {
+        model
+            .layers
+            .iter()
+            .map(|layer| Ok(layer.weight().to_vec2::<f32>()?))
+            .collect()
+    };
+
+    model.reinit(&mut varmap, 1)?;
+    let first = weights(&model)?;
+    model.reinit(&mut varmap, 2)?;
+    assert_ne!(weights(&model)?, first);
+    model.reinit(&mut varmap, 1)?;
+    assert_eq!(weights(&model)?, first);
+
+    Ok(())
+}
+
+#[test]
+fn vote_outcome_maps_the_binary_classes() -> anyhow::Result<()> {
+    assert_eq!(VoteOutcome::try_from(0)?, VoteOutcome::Reject);
+    assert_eq!(VoteOutcome::try_from(1)?, VoteOutcome::Pass);
+    assert!(VoteOutcome::try_from(2).is_err());
+    for outcome in [VoteOutcome::Reject, VoteOutcome::Pass] {
+        assert_eq!(VoteOutcome::try_from(u32::from(outcome))?, outcome);
+    }
+
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        seed: Some(6),
+        verbose: false,
+        ..Default::default()
+    };
+    let trained = fit(sample_dataset(&dev)?, &config, &dev, None)?.trained;
+    for votes in [[13f32, 22.], [30., 20.], [3., 10.]] {
+        let outcome = trained.model.predict_outcome(&votes)?;
+        assert_eq!(u32::from(outcome), trained.model.predict_one(&votes)?);
+    }
+
+    let three_classes = TrainConfig {
+        num_classes: 3,
+        ..config
+    };
+    let varmap = VarMap::new();
+    let model = MultiLevelPerceptron::new(
+        VarBuilder::from_varmap(&varmap, DType::F32, &dev),
+        &three_classes,
+    )?;
+    assert!(model.predict_outcome(&[13., 22.]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn csv_mixes_count_and_float_columns() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let path = std::env::temp_dir().join("utf-rnn-mixed-columns.csv");
+    std::fs::write(
+        &path,
+        "count,share,label\n15,0.25,1\n10,-0.75,0\n7,1.5e-1,1\n30,1,0\n",
+    )?;
+
+    let m = Dataset::from_csv(&path, &[0, 1], 2, &dev)?;
+    assert_eq!(m.train_votes.dtype(), DType::F32);
+    assert_eq!(
+        Tensor::cat(&[&m.train_votes, &m.test_votes], 0)?.to_vec2::<f32>()?,
+        [[15., 0.25], [10., -0.75], [7., 0.15], [30., 1.]]
+    );
+
+    for (csv, missing) in [
+        ("15,0.25,1\n10,,0\n", "2: missing value in column 1"),
+        ("15,NA,1\n10,0.5,0\n", "1: missing value in column 1"),
+        ("15,0.25,1\n10,0.5,\n", "2: missing label"),
+    ] {
+        std::fs::write(&path, csv)?;
+        let err = Dataset::from_csv(&path, &[0, 1], 2, &dev).unwrap_err();
+        assert!(err.to_string().contains(missing), "{err}");
+    }
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn predict_with_threshold_abstains_when_unsure() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        seed: Some(6),
+        verbose: false,
+        ..Default::default()
+    };
+    let trained = fit(sample_dataset(&dev)?, &config, &dev, None)?.trained;
+    let votes = Tensor::new(&[[13f32, 22.], [30., 20.], [3., 10.]], &dev)?;
+
+    // Every prediction of two classes is at least 50% confident.
+    let predictions = trained.model.predict(&votes)?;
+    let confident = trained.model.predict_with_threshold(&votes, 0.5)?;
+    assert_eq!(
+        confident,
+        predictions.into_iter().map(Some).collect::<Vec<_>>()
+    );
+
+    // No probability exceeds one, so every row abstains.
+    let abstained = trained.model.predict_with_threshold(&votes, 1.1)?;
+    assert_eq!(abstained, [None, None, None]);
+
+    Ok(())
+}
+
+#[test]
+fn init_seed_leaves_the_row_order_alone() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let m = sample_dataset(&dev)?;
+    let config = TrainConfig {
+        data_seed: Some(11),
+        init_seed: Some(1),
+        ..Default::default()
+    };
+    let reinitialized = TrainConfig {
+        init_seed: Some(2),
+        ..config.clone()
+    };
+    let reshuffled = TrainConfig {
+        data_seed: Some(12),
+        ..config.clone()
+    };
+
+    // The per-epoch row orders, as `fit` draws them.
+    let orders = |config: &TrainConfig| -> anyhow::Result<Vec<Vec<u32>>> {
+        let mut rng = config.data_rng();
+        (0..5)
+            .map(|_| {
+                let (_, results) = shuffle_rows(&m.train_votes, &m.train_results, &mut rng)?;
+                Ok(results.to_vec1::<u32>()?)
+            })
+            .collect()
+    };
+    assert_eq!(orders(&reinitialized)?, orders(&config)?);
+    assert_ne!(orders(&reshuffled)?, orders(&config)?);
+
+    // The weights follow the init seed instead.
+    let weights = |config: &TrainConfig| -> anyhow::Result<Vec<Vec<f32>>> {
+        let trained = fit(
+            m.clone(),
+            &TrainConfig {
+                epochs: 0,
+                verbose: false,
+                ..config.clone()
+            },
+            &dev,
+            None,
+        )?
+        .trained;
+        Ok(trained.model.layers[0].weight().to_vec2::<f32>()?)
+    };
+    assert_eq!(weights(&reshuffled)?, weights(&config)?);
+    assert_ne!(weights(&reinitialized)?, weights(&config)?);
+
+    Ok(())
+}
+
+#[test]
+fn decision_grid_covers_the_input_plane() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        seed: Some(6),
+        normalize: true,
+        verbose: false,
+        ..Default::default()
+    };
+    let trained = fit(sample_dataset(&dev)?, &config, &dev, None)?.trained;
+
+    let grid = decision_grid(&trained.model, (0., 40.), (0., 30.), 9)?;
+    assert_eq!(grid.len(), 9);
+    assert!(grid.iter().all(|row| row.len() == 9));
+    assert!(grid.iter().flatten().all(|&class| class < 2));
+    // The corner at (0, 30) is the point (x0, y8).
+    assert_eq!(grid[8][0], trained.model.predict_one(&[0., 30.])?);
+
+    assert!(decision_grid(&trained.model, (0., 1.), (0., 1.), 1).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn tracked_confusion_matrices_count_the_test_set() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let m = sample_dataset(&dev)?;
+    let test_len = m.test_results.dim(0)?;
+    let config = TrainConfig {
+        epochs: 10,
+        stop_on_accuracy: false,
+        track_confusion: true,
+        verbose: false,
+        seed: Some(4),
+        ..Default::default()
+    };
+    let trained = train(m, &config, &dev, &mut SilentObserver)?;
+
+    let matrices: Vec<&ConfusionMatrix> = trained
+        .metrics
+        .iter()
+        .map(|epoch| epoch.confusion.as_ref().expect("tracked every epoch"))
+        .collect();
+    for (epoch, confusion) in trained.metrics.iter().zip(&matrices) {
+        assert_eq!(confusion.total(), test_len);
+        assert!((100. * confusion.accuracy() - epoch.test_accuracy).abs() < 1e-3);
+    }
+    for pair in matrices.windows(2) {
+        let diff = pair[1].diff(pair[0])?;
+        // Samples only move between cells, so every row keeps its sum.
+        assert!(diff.iter().all(|row| row.iter().sum::<i64>() == 0));
+    }
+    assert!(matrices[0].diff(&ConfusionMatrix::new(3)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn target_accuracy_accepts_a_partly_correct_model() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        target_accuracy: 66.0,
+        verbose: false,
+        seed: Some(3),
+        ..Default::default()
+    };
+    let trained = train_until_converged(
+        sample_dataset(&dev)?,
+        &config,
+        MAX_ATTEMPTS,
+        &dev,
+        &mut SilentObserver,
+    )?;
+    // Two of the three test votes are enough.
+    let last = trained.metrics.last().unwrap();
+    assert!(last.test_accuracy >= 66.0);
+    // The early stop uses the same percentage.
+    let (_, earlier) = trained.metrics.split_last().unwrap();
+    assert!(earlier.iter().all(|e| e.test_accuracy < 66.0));
+
+    let err = UtfRnnError::NotTrained {
+        epochs: 10,
+        accuracy: 33.333,
+        target: 66.0,
+    };
+    assert!(
+        err.to_string().contains("32.67 points short of 66.00%"),
+        "{err}"
+    );
+
+    let unreachable = TrainConfig {
+        target_accuracy: 101.0,
+        ..config
+    };
+    assert!(validate_setup(&sample_dataset(&dev)?, &unreachable).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn plain_rows_train_save_load_and_predict() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let rows: Vec<Vec<f32>> = vec![
+        vec![15., 10.],
+        vec![10., 15.],
+        vec![5., 12.],
+        vec![30., 20.],
+        vec![16., 12.],
+        vec![13., 25.],
+        vec![6., 14.],
+        vec![31., 21.],
+        vec![13., 9.],
+        vec![8., 14.],
+    ];
+    let labels = vec![1, 0, 0, 1, 1, 0, 0, 1, 1, 0];
+    let config = TrainConfig {
+        normalize: true,
+        seed: Some(6),
+        verbose: false,
+        ..Default::default()
+    };
+
+    let predictor = Predictor::train_rows(&rows, &labels, &config, &dev)?;
+    let predictions = predictor.predict_rows(&rows)?;
+    assert_eq!(predictions.len(), rows.len());
+    assert!(predictions.iter().all(|&class| class < 2));
+
+    let dir = std::env::temp_dir().join("utf-rnn-plain-rows");
+    predictor.save(&dir)?;
+    let loaded = Predictor::load(&dir, &dev)?;
+    assert_eq!(loaded.predict_rows(&rows)?, predictions);
+
+    assert!(Predictor::train_rows(&rows, &labels[1..], &config, &dev).is_err());
+    assert!(loaded.predict_rows(&[vec![1., 2., 3.]]).is_err());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn inference_outputs_are_detached_from_the_graph() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig::default();
+    let varmap = VarMap::new();
+    let model =
+        MultiLevelPerceptron::new(VarBuilder::from_varmap(&varmap, DType::F32, &dev), &config)?;
+    let votes = Tensor::new(&[[13f32, 22.], [30., 20.]], &dev)?;
+    let weight = model.layers[0].weight();
+
+    // Training scores reach back to the weights...
+    let grads = model.forward(&votes)?.sum_all()?.backward()?;
+    assert!(grads.get(weight).is_some());
+
+    // ...the inference ones don't, however often they are computed.
+    for _ in 0..100 {
+        let logits = model.logits(&votes)?;
+        assert!(!logits.is_variable());
+        let grads = logits.sum_all()?.backward()?;
+        assert!(grads.get(weight).is_none());
+        model.predict(&votes)?;
+    }
+    let probabilities = model.predict_proba(&votes)?;
+    assert!(probabilities.sum_all()?.backward()?.get(weight).is_none());
+
+    // The inference pass itself runs on parameters that track nothing.
+    let detached = model.detached()?;
+    for layer in &detached.layers {
+        assert!(!layer.weight().is_variable());
+        assert!(layer.bias().map_or(true, |bias| !bias.is_variable()));
+    }
+    assert_eq!(
+        detached.forward(&votes)?.to_vec2::<f32>()?,
+        model.forward(&votes)?.to_vec2::<f32>()?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn lr_backoff_lowers_the_learning_rate_of_every_retry() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    let config = TrainConfig {
+        epochs: 1,
+        learning_rate: 0.05,
+        lr_backoff: 0.5,
+        seed: Some(20),
+        verbose: false,
+        ..Default::default()
+    };
+    // Contradicting labels for the same votes make every attempt fail.
+    let m = unlearnable_dataset(&dev)?;
+
+    let mut observer = RecordingObserver::default();
+    let err = train_until_converged(m, &config, 3, &dev, &mut observer)
+        .err()
+        .expect("no attempt converges");
+    assert!(matches!(err, UtfRnnError::NotConverged { attempts: 3, .. }));
+    assert_eq!(observer.attempts, [1, 2, 3]);
+    let learning_rates: Vec<f64> = observer.epochs.iter().map(|e| e.learning_rate).collect();
+    assert_eq!(learning_rates, [0.05, 0.05 * 0.5, 0.05 * 0.5 * 0.5]);
+
+    Ok(())
+}
+
+#[test]
+fn lr_backoff_rescues_a_diverging_first_attempt() -> anyhow::Result<()> {
+    let dev = Device::Cpu;
+    // Regression never reaches the target accuracy, so `accept_best` hands
+    // back the best attempt that did not diverge.
+    let config = TrainConfig {
+        task: Task::Regression,
+        hidden_sizes: vec![8],
+        epochs: 200,
+        normalize: true,
+        learning_rate: 1e4,
+        lr_backoff: 0.01,
+        accept_best: true,
+        seed: Some(3),
+        verbose: false,
+        ..Default::default()
+    };
+    let mut rows = Vec::new();
+    let mut scores = Vec::new();
+    for yes in 0..6 {
+        for no in 0..6 {
+            rows.push([yes as f32, no as f32]);
+            scores.push(0.5 * yes as f32 - 0.25 * no as f32 + 1.);
+        }
+    }
+    let votes = Tensor::from_slice(rows.as_flattened(), (rows.len(), 2), &dev)?;
+    let targets = Tensor::new(scores.as_slice(), &dev)?;
+    let m = Dataset {
+        train_votes: votes.clone(),
+        train_results: targets.clone(),
+        test_votes: votes,
+        test_results: targets,
+        validation: None,
+    };
+
+    let mut observer = RecordingObserver::default();
+    let trained = train_until_converged(m.clone(), &config, 4, &dev, &mut observer)?;
+    assert_eq!(observer.attempts, [1, 2, 3, 4]);
+    // Only a backed-off attempt got through all its epochs.
+    assert_eq!(trained.metrics.len(), 200);
+    assert!(trained.metrics[0].learning_rate <= 1e4 * 0.01 * 0.01);
+    assert!(trained.metrics[199].test_rmse.unwrap().is_finite());
+
+    // When every attempt diverges, the last divergence is returned.
+    let err = train_until_converged(m.clone(), &config, 2, &dev, &mut SilentObserver)
+        .err()
+        .expect("both attempts diverge");
+    assert!(matches!(
+        err,
+        UtfRnnError::Train(TrainError::NonFiniteLoss { .. })
+    ));
+
+    // Without a backoff every attempt is still made, each diverging again.
+    let no_backoff = TrainConfig {
+        lr_backoff: 1.0,
+        ..config
+    };
+    let mut observer = RecordingObserver::default();
+    let err = train_until_converged(m, &no_backoff, 4, &dev, &mut observer)
+        .err()
+        .expect("every attempt diverges");
+    assert!(matches!(
+        err,
+        UtfRnnError::Train(TrainError::NonFiniteLoss { .. })
+    ));
+    assert_eq!(observer.attempts, [1, 2, 3, 4]);
 
     Ok(())
 }
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+/// Maps every distinct `char` of a corpus to a contiguous `u32` id.
+///
+/// Ids are assigned in codepoint order, so the same corpus always yields the
+/// same vocabulary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CharVocab {
+    chars: Vec<char>,
+    ids: HashMap<char, u32>,
+}
+
+impl CharVocab {
+    pub fn new(corpus: &str) -> Self {
+        let mut chars: Vec<char> = corpus.chars().collect();
+        chars.sort_unstable();
+        chars.dedup();
+        let ids = chars
+            .iter()
+            .enumerate()
+            .map(|(id, &c)| (c, id as u32))
+            .collect();
+        Self { chars, ids }
+    }
+
+    /// Number of distinct characters in the vocabulary.
+    pub fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// The vocabulary characters, indexed by id.
+    pub fn chars(&self) -> &[char] {
+        &self.chars
+    }
+
+    pub fn id(&self, c: char) -> Option<u32> {
+        self.ids.get(&c).copied()
+    }
+
+    pub fn char(&self, id: u32) -> Option<char> {
+        self.chars.get(id as usize).copied()
+    }
+
+    /// Encodes `text` as ids; characters missing from the vocabulary are
+    /// skipped.
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        text.chars().filter_map(|c| self.id(c)).collect()
+    }
+
+    /// Decodes ids back into text; ids outside the vocabulary are skipped.
+    pub fn decode(&self, ids: &[u32]) -> String {
+        ids.iter().filter_map(|&id| self.char(id)).collect()
+    }
+}